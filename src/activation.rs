@@ -0,0 +1,157 @@
+use crate::{
+    cli::{ActivateCmd, DeactivateCmd, FormatOptions, PinCmd, RepairCmd, UnpinCmd},
+    config::Config,
+    get_current_dir_or_exit,
+    hook::{self, Modifications, VariableOutputMode, DISABLED_KEY},
+    loader, output,
+    shadowenv::Shadowenv,
+    undo, unsafe_getppid,
+};
+use anyhow::{anyhow, Error};
+use std::{collections::HashMap, env};
+
+/// Print the modifications restoring the environment from before the active shadowenv, and
+/// disabling the hook for this shell.
+pub fn deactivate(cmd: DeactivateCmd) -> Result<(), Error> {
+    let (_, mut data) = undo::parse(&Shadowenv::from_env())?;
+    // Deactivating drops the pin too, or activating again would bring back the pinned shadowenv.
+    data.pinned.clear();
+    // The same as leaving the directory: no programs to run on top.
+    let shadowenv = Shadowenv::new(env::vars().collect(), data, 0, cmd.clobber);
+    let config = Config::load()?;
+
+    let mut modifications = Modifications::from_shadowenv(&shadowenv)?;
+    hook::store_session(
+        &mut modifications,
+        &shadowenv,
+        hook::session_owner(&config, shell_pid),
+    )?;
+    modifications
+        .exported
+        .insert(DISABLED_KEY.to_string(), Some("1".to_string()));
+
+    let mode = VariableOutputMode::from_options(&cmd.format);
+    hook::print_modifications(&modifications, &mode);
+    if let VariableOutputMode::Posix | VariableOutputMode::Fish = mode {
+        output::print_activation_to_tty(&shadowenv, &config);
+    }
+    Ok(())
+}
+
+/// Print the modifications loading the shadowenv for the current directory, and enabling the hook
+/// again.
+pub fn activate(cmd: ActivateCmd) -> Result<(), Error> {
+    let mut vars: HashMap<String, String> = env::vars().collect();
+    vars.remove(DISABLED_KEY);
+
+    let mut extra = HashMap::new();
+    extra.insert(DISABLED_KEY.to_string(), None);
+    reload(vars, Shadowenv::from_env(), extra, cmd.clobber, &cmd.format)
+}
+
+/// Print the modifications pinning the shadowenv of the current directory: until unpinned, the
+/// hook keeps loading it wherever the shell goes.
+pub fn pin(cmd: PinCmd) -> Result<(), Error> {
+    let (hash, mut data) = undo::parse(&Shadowenv::from_env())?;
+    let roots = loader::find_shadowenv_paths(&get_current_dir_or_exit())?;
+    if roots.is_empty() {
+        return Err(anyhow!("no shadowenv to pin in this directory"));
+    }
+    data.pinned = roots;
+
+    reload(
+        env::vars().collect(),
+        undo::format(hash, &data)?,
+        HashMap::new(),
+        cmd.clobber,
+        &cmd.format,
+    )
+}
+
+/// Print the modifications unpinning the active shadowenv, loading the one of the current
+/// directory instead.
+pub fn unpin(cmd: UnpinCmd) -> Result<(), Error> {
+    let (hash, mut data) = undo::parse(&Shadowenv::from_env())?;
+    if data.pinned.is_empty() {
+        return Err(anyhow!("no shadowenv is pinned"));
+    }
+    data.pinned.clear();
+
+    reload(
+        env::vars().collect(),
+        undo::format(hash, &data)?,
+        HashMap::new(),
+        cmd.clobber,
+        &cmd.format,
+    )
+}
+
+/// Print the modifications replacing a corrupt `__shadowenv_data`: the shadowenv of the current
+/// directory is loaded as if none was active.
+pub fn repair(cmd: RepairCmd) -> Result<(), Error> {
+    let shadowenv_data = Shadowenv::from_env();
+    if undo::parse(&shadowenv_data).is_ok() {
+        eprintln!("shadowenv: __shadowenv_data is fine, nothing to repair");
+        return Ok(());
+    }
+
+    reload(
+        env::vars().collect(),
+        String::new(),
+        HashMap::new(),
+        false,
+        &cmd.format,
+    )
+}
+
+/// Load the shadowenv `shadowenv_data` leads to from the current directory, even if it's already
+/// active, and print its modifications along with `extra` ones.
+pub fn reload(
+    vars: HashMap<String, String>,
+    shadowenv_data: String,
+    extra: HashMap<String, Option<String>>,
+    clobber: bool,
+    format: &FormatOptions,
+) -> Result<(), Error> {
+    let config = Config::load()?;
+    let loaded = hook::load_env(
+        get_current_dir_or_exit(),
+        vars,
+        shadowenv_data.clone(),
+        true,
+        clobber,
+        &config,
+    )?;
+
+    let mode = VariableOutputMode::from_options(format);
+    match loaded {
+        Some((shadowenv, config)) => {
+            let mut modifications = Modifications::from_shadowenv(&shadowenv)?;
+            hook::store_session(
+                &mut modifications,
+                &shadowenv,
+                hook::session_owner(&config, shell_pid),
+            )?;
+            modifications.exported.extend(extra);
+            hook::print_modifications(&modifications, &mode);
+            if let VariableOutputMode::Posix | VariableOutputMode::Fish = mode {
+                output::print_activation_to_tty(&shadowenv, &config);
+            }
+        }
+        None => {
+            // Nothing to load or unload, but the data itself may have changed.
+            let mut exports = extra;
+            if shadowenv_data != Shadowenv::from_env() {
+                let value = Some(shadowenv_data).filter(|data| !data.is_empty());
+                exports.insert("__shadowenv_data".to_string(), value);
+            }
+            hook::print_modifications(&Modifications::new(exports, vec![]), &mode);
+        }
+    }
+    Ok(())
+}
+
+/// These commands are meant to be eval'd by the shell, so it's our parent.
+fn shell_pid() -> u32 {
+    unsafe_getppid().expect("shadowenv bug: unable to get parent pid")
+}
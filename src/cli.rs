@@ -4,13 +4,144 @@ use clap::{Args, Parser, Subcommand};
 #[clap(about, version)]
 #[command(propagate_version = true)]
 pub enum ShadowenvApp {
+    Activate(ActivateCmd),
+    #[command(subcommand)]
+    Config(ConfigCmd),
+    Daemon(DaemonCmd),
+    #[command(visible_alias = "unshadow")]
+    Deactivate(DeactivateCmd),
     Diff(DiffCmd),
     Exec(ExecCmd),
+    Features(FeaturesCmd),
     Hook(HookCmd),
     #[command(subcommand)]
     Init(InitCmd),
+    Pin(PinCmd),
+    #[command(subcommand)]
+    Profile(ProfileCmd),
+    Repair(RepairCmd),
     Trust(TrustCmd),
+    Unpin(UnpinCmd),
     PromptWidget(PromptWidgetCmd),
+    Watch(WatchCmd),
+}
+
+/// Undo `shadowenv deactivate`: load the shadowenv for the current directory again, and let the
+/// hook do its job from now on. Meant to be eval'd like `shadowenv hook`.
+#[derive(clap::Args, Debug)]
+pub struct ActivateCmd {
+    /// Clobber overridden environment variables when unshadowing.
+    #[arg(long)]
+    pub clobber: bool,
+
+    #[command(flatten)]
+    pub format: FormatOptions,
+}
+
+/// Leave the active shadowenv while staying in the directory, and keep the hook from loading any
+/// shadowenv in this shell until `shadowenv activate`.
+///
+/// Prints the variable assignments restoring the environment from before the shadowenv was loaded,
+/// so it's meant to be eval'd: `eval "$(shadowenv deactivate)"`, or
+/// `shadowenv deactivate --fish | source` in fish.
+#[derive(clap::Args, Debug)]
+pub struct DeactivateCmd {
+    /// Clobber overridden environment variables when unshadowing.
+    #[arg(long)]
+    pub clobber: bool,
+
+    #[command(flatten)]
+    pub format: FormatOptions,
+}
+
+/// Keep the shadowenv of the current directory active in this shell, wherever it goes, until
+/// `shadowenv unpin`. Meant to be eval'd like `shadowenv hook`.
+#[derive(clap::Args, Debug)]
+pub struct PinCmd {
+    /// Clobber overridden environment variables when unshadowing.
+    #[arg(long)]
+    pub clobber: bool,
+
+    #[command(flatten)]
+    pub format: FormatOptions,
+}
+
+/// Undo `shadowenv pin`: load the shadowenv of the current directory again, if there is one.
+/// Meant to be eval'd like `shadowenv hook`.
+#[derive(clap::Args, Debug)]
+pub struct UnpinCmd {
+    /// Clobber overridden environment variables when unshadowing.
+    #[arg(long)]
+    pub clobber: bool,
+
+    #[command(flatten)]
+    pub format: FormatOptions,
+}
+
+/// Replace a corrupt __shadowenv_data, e.g. truncated or mangled by a tool quoting it, and load the
+/// shadowenv of the current directory from scratch. What the shadowenv that was active changed
+/// can't be undone anymore. Meant to be eval'd like `shadowenv hook`.
+#[derive(clap::Args, Debug)]
+pub struct RepairCmd {
+    #[command(flatten)]
+    pub format: FormatOptions,
+}
+
+/// Select the profile loaded on top of each shadowenv, from its .shadowenv.d/profiles/<name>.
+/// `use` and `clear` are meant to be eval'd like `shadowenv hook`.
+#[derive(Subcommand, Debug)]
+#[clap(disable_help_subcommand = true)]
+pub enum ProfileCmd {
+    /// Load the shadowenv with this profile, by setting SHADOWENV_PROFILE.
+    Use(ProfileUseCmd),
+    /// Load the shadowenv without a profile, by unsetting SHADOWENV_PROFILE.
+    Clear(ProfileClearCmd),
+    /// List the profiles the shadowenv of the current directory defines. The selected one is
+    /// marked with a `*`.
+    List,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ProfileUseCmd {
+    pub name: String,
+
+    /// Clobber overridden environment variables when unshadowing.
+    #[arg(long)]
+    pub clobber: bool,
+
+    #[command(flatten)]
+    pub format: FormatOptions,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ProfileClearCmd {
+    /// Clobber overridden environment variables when unshadowing.
+    #[arg(long)]
+    pub clobber: bool,
+
+    #[command(flatten)]
+    pub format: FormatOptions,
+}
+
+/// Inspect the shadowenv configuration.
+#[derive(Subcommand, Debug)]
+#[clap(disable_help_subcommand = true)]
+pub enum ConfigCmd {
+    /// Print the configuration that applies in the current directory: the user config in
+    /// ~/.config/shadowenv/config.toml, overridden by each shadowenv's .shadowenv.d/config.toml.
+    Show,
+}
+
+/// Serve shadowenv over a Unix socket, for editors and other long-running tools.
+///
+/// Requests and responses are newline-delimited JSON. Clients can send
+/// `{"cmd":"load","dir":...,"env":{...}}` to get the modifications for a directory, and
+/// `{"cmd":"subscribe","dir":...}` to be notified when its shadowenv or trust status changes.
+#[derive(clap::Args, Debug)]
+pub struct DaemonCmd {
+    /// Listen on this socket instead of $XDG_RUNTIME_DIR/shadowenv/daemon.sock.
+    #[arg(long)]
+    pub socket: Option<String>,
 }
 
 /// Execute a command after loading the environment from the current directory.
@@ -20,6 +151,41 @@ pub struct ExecCmd {
     #[arg(long)]
     pub dir: Option<String>,
 
+    /// Start from a minimal environment (HOME, USER, LOGNAME, PATH, SHELL, TERM, LANG and
+    /// TMPDIR, with the active shadowenv undone) rather than the inherited one.
+    #[arg(long)]
+    pub clear_env: bool,
+
+    /// Set a variable before evaluating the shadowenv. Can be repeated.
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    pub env: Vec<(String, String)>,
+
+    /// Read variables to set before evaluating the shadowenv from a file of KEY=VALUE lines, with
+    /// values optionally quoted. Variables from --env take precedence.
+    #[arg(long, value_name = "FILE")]
+    pub env_file: Vec<String>,
+
+    /// Clobber overridden environment variables when undoing the active shadowenv.
+    #[arg(long)]
+    pub clobber: bool,
+
+    /// Don't load any shadowenv: run the command with the active shadowenv undone.
+    #[arg(long, conflicts_with = "dir")]
+    pub no_shadowenv: bool,
+
+    /// Leave __shadowenv_data out of the command's environment, e.g. when it's passed on to a
+    /// container or a sandbox. Tools run from it then can't tell a shadowenv is active.
+    #[arg(long)]
+    pub strip_metadata: bool,
+
+    /// Print the resulting environment instead of executing a command.
+    #[arg(long, visible_alias = "print-env")]
+    pub print: bool,
+
+    /// How to print the environment with --print.
+    #[command(flatten)]
+    pub format: FormatOptions,
+
     /// The command to execute if there are no arguments.
     pub cmd_argv0: Option<String>,
 
@@ -28,6 +194,13 @@ pub struct ExecCmd {
     pub cmd_argv: Vec<String>,
 }
 
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got `{}`", s)),
+    }
+}
+
 /// Display a diff of changed environment variables.
 #[derive(Args, Debug)]
 pub struct DiffCmd {
@@ -38,6 +211,51 @@ pub struct DiffCmd {
     /// Show all environment variables, not just those that change.
     #[arg(long, short)]
     pub verbose: bool,
+
+    /// How to print the diff.
+    #[arg(long, value_enum, default_value_t = DiffFormat::Text)]
+    pub format: DiffFormat,
+
+    /// How to show changes to pathlists like PATH in the text format.
+    #[arg(long, value_enum, default_value_t = PathsView::Full)]
+    pub paths: PathsView,
+
+    /// Rather than showing what the active shadowenv changed, compare the environment of this
+    /// directory...
+    #[arg(long, requires = "to", value_name = "DIR")]
+    pub from: Option<String>,
+
+    /// ...with the environment of this one.
+    #[arg(long, requires = "from", value_name = "DIR")]
+    pub to: Option<String>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathsView {
+    /// The whole value before and after, with changed entries highlighted.
+    Full,
+    /// Only the changed entries and their positions, on one line: `PATH +[0] /bin  -[3] /sbin`.
+    Compact,
+    /// Only the changed entries and their positions, one per line, aligned in columns.
+    Columns,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffFormat {
+    /// One `- NAME=value`/`+ NAME=value` line per change.
+    Text,
+    /// A JSON document describing each changed variable, for tools.
+    Json,
+    /// A patch-like diff, with one line per pathlist entry.
+    Unified,
+}
+
+/// List the features provided by the active shadowenv, e.g. `ruby:3.3.0`.
+#[derive(clap::Args, Debug)]
+pub struct FeaturesCmd {
+    /// Print the features as JSON.
+    #[arg(long)]
+    pub json: bool,
 }
 
 /// Runs the shell hook. You shouldn't need to run this manually.
@@ -59,6 +277,11 @@ pub struct HookCmd {
     #[arg(long)]
     pub clobber: bool,
 
+    /// With --json or --pretty-json, replace the values of secrets with a fingerprint. The output
+    /// is then only fit for display.
+    #[arg(long)]
+    pub redact: bool,
+
     #[command(flatten)]
     pub format: FormatOptions,
 }
@@ -118,4 +341,29 @@ pub struct InitOptions {
 
 /// Print a little glyph you can include in a shell prompt to indicate that shadowenv is active.
 #[derive(clap::Args, Debug)]
-pub struct PromptWidgetCmd {}
+pub struct PromptWidgetCmd {
+    /// Print this instead of the glyph. Placeholders: {root}, {roots}, {features}, {hash}.
+    #[arg(long)]
+    pub format: Option<String>,
+
+    /// Describe the active shadowenv as JSON, for prompt frameworks.
+    #[arg(long, conflicts_with = "format")]
+    pub json: bool,
+}
+
+/// Watch the shadowenv for a directory and print a new hook payload whenever it changes.
+///
+/// Every payload is computed against the environment `shadowenv watch` was started with.
+#[derive(clap::Args, Debug)]
+pub struct WatchCmd {
+    /// Instead of watching the shadowenv for the current directory, watch the one for this one.
+    #[arg(long)]
+    pub dir: Option<String>,
+
+    /// Clobber overridden environment variables when unshadowing.
+    #[arg(long)]
+    pub clobber: bool,
+
+    #[command(flatten)]
+    pub format: FormatOptions,
+}
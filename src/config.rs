@@ -0,0 +1,418 @@
+use crate::{cli::ConfigCmd, get_current_dir_or_exit, loader, secrets::SecretMatcher, trust};
+use anyhow::{anyhow, Error};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The config of a shadowenv, in its `.shadowenv.d`.
+pub const PROJECT_CONFIG_NAME: &str = "config.toml";
+const DEFAULT_COOLDOWN_SECONDS: u64 = 5;
+
+/// User configuration, read from `~/.config/shadowenv/config.toml`. Every setting is optional.
+///
+/// ```toml
+/// color = true
+/// clobber = false
+/// trust = "signed"
+/// state = "env"
+/// allowed_builtins = ["env/set", "env/prepend-to-pathlist", "provide"]
+///
+/// [banner]
+/// verbosity = "full"
+/// template = "{shadowenv}{dirs} {features}"
+///
+/// [prompt_widget]
+/// template = "({roots})"
+///
+/// [errors]
+/// cooldown = 5
+///
+/// [secrets]
+/// patterns = ["*_TOKEN", "AWS_*"]
+/// hash_values = false
+///
+/// [secrets.commands]
+/// github-token = ["pass", "show", "dev/github-token"]
+/// ```
+///
+/// Each shadowenv can override some of these in its own `.shadowenv.d/config.toml`: see
+/// [ProjectConfig].
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Whether to use colors at all. Setting NO_COLOR in the environment overrides this.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<bool>,
+    /// Clobber overridden environment variables when unshadowing, as if `--clobber` was passed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clobber: Option<bool>,
+    /// How shadowenvs must be trusted before they're run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trust: Option<TrustMode>,
+    /// Where the data needed to undo the active shadowenv is kept.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<StateMode>,
+    /// The shadowlisp builtins shadowenv programs may call, like `env/set`. All of them if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_builtins: Option<Vec<String>>,
+    pub banner: BannerConfig,
+    pub prompt_widget: PromptWidgetConfig,
+    pub errors: ErrorsConfig,
+    pub secrets: SecretsConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustMode {
+    /// Only run shadowenvs trusted with `shadowenv trust` (the default).
+    Signed,
+    /// Run every shadowenv without asking. Only sensible in throwaway environments like CI.
+    Disabled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StateMode {
+    /// In `__shadowenv_data` itself (the default).
+    Env,
+    /// In files of the runtime dir, owned by each shell: `__shadowenv_data` only points to one, so
+    /// the data doesn't leak into every process started from the shell.
+    File,
+}
+
+/// The line printed to the terminal when a shadowenv is activated or deactivated.
+///
+/// Placeholders: `{shadowenv}`, `{dirs}` (the `[++|-]` marker), `{roots}`, `{added}`, `{removed}`,
+/// `{features}` (those that weren't provided before), `{all_features}`, `{hash}`, `{profile}` and
+/// `{pinned}` ("pinned" while the shadowenv is pinned with `shadowenv pin`).
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BannerConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verbosity: Option<Verbosity>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Verbosity {
+    /// The banner, with the features that were provided (the default).
+    Full,
+    /// The banner without the features.
+    Minimal,
+    /// No banner at all, like with SHADOWENV_SILENT set.
+    Silent,
+}
+
+/// The output of `shadowenv prompt-widget` while a shadowenv is active.
+///
+/// Placeholders: `{root}` (the name of the nearest shadowenv's directory), `{roots}`, `{features}`,
+/// `{hash}`, `{profile}` and `{pinned}`.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromptWidgetConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErrorsConfig {
+    /// For how many seconds an error isn't repeated in the same shell.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cooldown: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretsConfig {
+    /// Globs matching the names of the variables whose values must be redacted when displayed,
+    /// like those set with `env/set-secret`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patterns: Option<Vec<String>>,
+    /// The commands `secret/command` may run, by name, as argv lists.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commands: Option<BTreeMap<String, Vec<String>>>,
+    /// Keep only a keyed hash of every value a shadowenv sets in `__shadowenv_data`, as is done
+    /// for secrets, since it's visible to every child process.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash_values: Option<bool>,
+}
+
+/// The settings a shadowenv can override in its `.shadowenv.d/config.toml`.
+///
+/// Anything that could weaken security (`trust`, `state`, `errors`, `secrets`, or allowing more
+/// builtins) can only be set in the user config: `allowed_builtins` here can only narrow down the
+/// user's list.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProjectConfig {
+    pub clobber: Option<bool>,
+    pub allowed_builtins: Option<Vec<String>>,
+    pub banner: BannerConfig,
+}
+
+impl Config {
+    /// Load the user config. A missing file is the same as an empty one.
+    pub fn load() -> Result<Config, Error> {
+        let path = Config::path()?;
+        match read(&path)? {
+            Some(contents) => Config::parse(&contents)
+                .map_err(|err| anyhow!("invalid config in {}: {}", path.display(), err)),
+            None => Ok(Config::default()),
+        }
+    }
+
+    pub fn path() -> Result<PathBuf, Error> {
+        Ok(PathBuf::from(format!(
+            "{}/.config/shadowenv/config.toml",
+            env::var("HOME")?
+        )))
+    }
+
+    fn parse(contents: &str) -> Result<Config, Error> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// This config, overridden by the config of each of `roots` (the `.shadowenv.d` directories of
+    /// a shadowenv, nearest first).
+    pub fn layered(&self, roots: &[PathBuf]) -> Result<Config, Error> {
+        let mut config = self.clone();
+        // Outermost first, so that the nearest shadowenv has the last word.
+        for root in roots.iter().rev() {
+            let path = root.join(PROJECT_CONFIG_NAME);
+            if let Some(contents) = read(&path)? {
+                let project: ProjectConfig = toml::from_str(&contents)
+                    .map_err(|err| anyhow!("invalid config in {}: {}", path.display(), err))?;
+                config.merge(project);
+            }
+        }
+        Ok(config)
+    }
+
+    fn merge(&mut self, project: ProjectConfig) {
+        if project.clobber.is_some() {
+            self.clobber = project.clobber;
+        }
+        if let Some(allowed) = project.allowed_builtins {
+            self.allowed_builtins = Some(match self.allowed_builtins.take() {
+                Some(current) => allowed
+                    .into_iter()
+                    .filter(|name| current.contains(name))
+                    .collect(),
+                None => allowed,
+            });
+        }
+        if project.banner.verbosity.is_some() {
+            self.banner.verbosity = project.banner.verbosity;
+        }
+        if project.banner.template.is_some() {
+            self.banner.template = project.banner.template;
+        }
+    }
+
+    /// Whether output meant for humans should be colored. See https://no-color.org.
+    pub fn color(&self) -> bool {
+        match env::var("NO_COLOR") {
+            Ok(value) if !value.is_empty() => false,
+            _ => self.color.unwrap_or(true),
+        }
+    }
+
+    pub fn clobber(&self) -> bool {
+        self.clobber.unwrap_or(false)
+    }
+
+    pub fn trust(&self) -> TrustMode {
+        self.trust.unwrap_or(TrustMode::Signed)
+    }
+
+    pub fn state(&self) -> StateMode {
+        self.state.unwrap_or(StateMode::Env)
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        self.banner.verbosity.unwrap_or(Verbosity::Full)
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.errors.cooldown.unwrap_or(DEFAULT_COOLDOWN_SECONDS))
+    }
+
+    pub fn secret_matcher(&self) -> SecretMatcher {
+        SecretMatcher::new(self.secrets.patterns.as_deref().unwrap_or_default())
+    }
+
+    pub fn hash_values(&self) -> bool {
+        self.secrets.hash_values.unwrap_or(false)
+    }
+
+    pub fn builtin_allowed(&self, name: &str) -> bool {
+        match &self.allowed_builtins {
+            Some(allowed) => allowed.iter().any(|allowed| allowed == name),
+            None => true,
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<Option<String>, Error> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Print the config that applies in the current directory, with the files it was read from.
+pub fn run(cmd: ConfigCmd) -> Result<(), Error> {
+    match cmd {
+        ConfigCmd::Show => {
+            let config = Config::load()?;
+            let (roots, untrusted): (Vec<PathBuf>, Vec<PathBuf>) =
+                loader::find_shadowenv_paths(&get_current_dir_or_exit())?
+                    .into_iter()
+                    .partition(|root| {
+                        config.trust() == TrustMode::Disabled
+                            || trust::ensure_dir_tree_trusted(std::slice::from_ref(root)).is_ok()
+                    });
+            // Like the hook, don't read anything from shadowenvs that aren't trusted.
+            let config = config.layered(&roots)?;
+
+            let files = std::iter::once(Config::path()?).chain(
+                roots
+                    .iter()
+                    .rev()
+                    .map(|root| root.join(PROJECT_CONFIG_NAME)),
+            );
+            for file in files {
+                if file.exists() {
+                    println!("# {}", file.display());
+                }
+            }
+            for root in untrusted {
+                println!("# {} (ignored: untrusted)", root.display());
+            }
+            print!("{}", toml::to_string(&config)?);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn parse_empty_config() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn parse_full_config() {
+        let config = Config::parse(
+            r#"
+            color = false
+            clobber = true
+            trust = "disabled"
+            state = "file"
+            allowed_builtins = ["env/set"]
+
+            [banner]
+            verbosity = "minimal"
+            template = "{shadowenv} {roots}"
+
+            [prompt_widget]
+            template = "[{hash}]"
+
+            [errors]
+            cooldown = 30
+
+            [secrets]
+            patterns = ["*_TOKEN"]
+            hash_values = true
+            commands = { github = ["pass", "show", "github"] }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.color, Some(false));
+        assert!(config.clobber());
+        assert_eq!(config.trust(), TrustMode::Disabled);
+        assert_eq!(config.state(), StateMode::File);
+        assert!(config.builtin_allowed("env/set"));
+        assert!(!config.builtin_allowed("env/get"));
+        assert_eq!(config.verbosity(), Verbosity::Minimal);
+        assert_eq!(
+            config.banner.template,
+            Some("{shadowenv} {roots}".to_string())
+        );
+        assert_eq!(config.prompt_widget.template, Some("[{hash}]".to_string()));
+        assert_eq!(config.cooldown(), Duration::from_secs(30));
+        assert!(config.secret_matcher().matches("GITHUB_TOKEN"));
+        assert!(config.hash_values());
+        assert_eq!(
+            config.secrets.commands,
+            Some(BTreeMap::from([(
+                "github".to_string(),
+                vec!["pass".to_string(), "show".to_string(), "github".to_string()]
+            )]))
+        );
+    }
+
+    #[test]
+    fn parse_rejects_unknown_settings() {
+        assert!(Config::parse("colour = true").is_err());
+    }
+
+    #[test]
+    fn project_configs_are_layered() {
+        let temp_dir = tempdir().unwrap();
+        let outer = temp_dir.path().join("outer");
+        let inner = temp_dir.path().join("inner");
+        fs::create_dir_all(&outer).unwrap();
+        fs::create_dir_all(&inner).unwrap();
+        fs::write(
+            outer.join(PROJECT_CONFIG_NAME),
+            "clobber = true\nallowed_builtins = [\"env/set\", \"provide\", \"expand-path\"]\n[banner]\nverbosity = \"minimal\"",
+        )
+        .unwrap();
+        fs::write(
+            inner.join(PROJECT_CONFIG_NAME),
+            "[banner]\nverbosity = \"silent\"",
+        )
+        .unwrap();
+
+        let global = Config::parse(r#"allowed_builtins = ["env/set", "provide"]"#).unwrap();
+        let config = global.layered(&[inner, outer]).unwrap();
+
+        assert!(config.clobber());
+        assert_eq!(config.verbosity(), Verbosity::Silent);
+        // A shadowenv can't allow more than the user did.
+        assert_eq!(
+            config.allowed_builtins,
+            Some(vec!["env/set".to_string(), "provide".to_string()])
+        );
+    }
+
+    #[test]
+    fn project_configs_cant_change_security_settings() {
+        let temp_dir = tempdir().unwrap();
+        fs::write(
+            temp_dir.path().join(PROJECT_CONFIG_NAME),
+            r#"trust = "disabled""#,
+        )
+        .unwrap();
+
+        let err = Config::default()
+            .layered(&[temp_dir.path().to_path_buf()])
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown field `trust`"));
+    }
+}
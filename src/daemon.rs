@@ -0,0 +1,540 @@
+use crate::{
+    cli::DaemonCmd,
+    config::{Config, TrustMode},
+    hook::{self, Modifications},
+    loader, trust, watch, xdg,
+};
+use anyhow::Error;
+use notify::RecommendedWatcher;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+const SOCKET_NAME: &str = "daemon.sock";
+/// A client that doesn't read its notifications for this long is dropped, rather than holding up
+/// everybody else's.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Evaluating shadowlisp changes the working directory of the whole process, so only one
+/// evaluation may run at a time.
+static EVAL_LOCK: Mutex<()> = Mutex::new(());
+
+/// Wakes up the thread watching the paths that matter to subscribers, when they change or when
+/// there are new subscriptions to watch for.
+static WAKE: OnceLock<Sender<()>> = OnceLock::new();
+
+/// One request per line, as JSON.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+enum Request {
+    /// Compute the modifications needed to load the shadowenv for `dir`, given the client's env.
+    /// If `env` is omitted, the daemon's own environment is used.
+    Load {
+        dir: PathBuf,
+        #[serde(default)]
+        env: Option<HashMap<String, String>>,
+        #[serde(default)]
+        force: bool,
+        #[serde(default)]
+        clobber: bool,
+    },
+    /// Get notified whenever the shadowenv programs or trust status for `dir` change. The profile
+    /// selected in `env`, or in the daemon's own environment if it's omitted, is part of the hash.
+    Subscribe {
+        dir: PathBuf,
+        #[serde(default)]
+        env: Option<HashMap<String, String>>,
+    },
+    Unsubscribe {
+        dir: PathBuf,
+    },
+    Ping,
+}
+
+/// One response or notification per line, as JSON.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Response {
+    Load {
+        dir: PathBuf,
+        #[serde(flatten)]
+        modifications: Modifications,
+    },
+    Subscribed {
+        dir: PathBuf,
+    },
+    Unsubscribed {
+        dir: PathBuf,
+    },
+    Changed {
+        dir: PathBuf,
+        hash: Option<String>,
+        trusted: bool,
+    },
+    Pong,
+    Error {
+        message: String,
+    },
+}
+
+/// What the client is told about: the hash of all sources that apply to a dir, and whether
+/// they're trusted.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Fingerprint {
+    hash: Option<u64>,
+    trusted: bool,
+}
+
+type Writer = Arc<Mutex<UnixStream>>;
+
+struct Subscription {
+    dir: PathBuf,
+    profile: Option<String>,
+    fingerprint: Option<Fingerprint>,
+    writer: Writer,
+}
+
+type Subscriptions = Arc<Mutex<Vec<Subscription>>>;
+
+/// Listen on a Unix socket and answer requests from editor plugins until killed.
+pub fn run(cmd: DaemonCmd) -> Result<(), Error> {
+    let path = match cmd.socket {
+        Some(path) => PathBuf::from(path),
+        None => socket_path()?,
+    };
+    let listener = bind(&path)?;
+    eprintln!("shadowenv daemon listening on {}", path.display());
+
+    let subscriptions: Subscriptions = Arc::new(Mutex::new(vec![]));
+
+    let (tx, rx) = mpsc::channel();
+    let wake = tx.clone();
+    let watcher = notify::recommended_watcher(move |_| {
+        let _ = wake.send(());
+    })?;
+    let _ = WAKE.set(tx);
+    let watched = subscriptions.clone();
+    thread::spawn(move || watch_subscriptions(&watched, watcher, rx));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let subscriptions = subscriptions.clone();
+        thread::spawn(move || {
+            let _ = serve(stream, subscriptions);
+        });
+    }
+    Ok(())
+}
+
+/// The default socket location: `$XDG_RUNTIME_DIR/shadowenv/daemon.sock`.
+pub fn socket_path() -> Result<PathBuf, Error> {
+    Ok(xdg::runtime_dir()?.join(SOCKET_NAME))
+}
+
+fn bind(path: &Path) -> Result<UnixListener, Error> {
+    match UnixListener::bind(path) {
+        Err(ref e) if e.kind() == ErrorKind::AddrInUse => {
+            // A socket nobody is listening on anymore is left over from a previous daemon.
+            if UnixStream::connect(path).is_ok() {
+                return Err(anyhow::anyhow!(
+                    "another shadowenv daemon is already listening on {}",
+                    path.display()
+                ));
+            }
+            fs::remove_file(path)?;
+            Ok(UnixListener::bind(path)?)
+        }
+        r => Ok(r?),
+    }
+}
+
+fn serve(stream: UnixStream, subscriptions: Subscriptions) -> Result<(), Error> {
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let writer: Writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let reader = BufReader::new(stream);
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => handle(request, &writer, &subscriptions),
+            Err(err) => Response::Error {
+                message: format!("invalid request: {}", err),
+            },
+        };
+        send(&writer, &response)?;
+    }
+
+    // The client hung up: forget about anything it subscribed to.
+    subscriptions
+        .lock()
+        .unwrap()
+        .retain(|sub| !Arc::ptr_eq(&sub.writer, &writer));
+    Ok(())
+}
+
+fn handle(request: Request, writer: &Writer, subscriptions: &Subscriptions) -> Response {
+    // Relative to what? The daemon's working directory means nothing to clients.
+    if let Request::Load { dir, .. }
+    | Request::Subscribe { dir, .. }
+    | Request::Unsubscribe { dir } = &request
+    {
+        if !dir.is_absolute() {
+            return Response::Error {
+                message: format!("dir must be an absolute path, not {}", dir.display()),
+            };
+        }
+    }
+
+    match request {
+        Request::Load {
+            dir,
+            env,
+            force,
+            clobber,
+        } => match load(
+            &dir,
+            env.unwrap_or_else(|| env::vars().collect()),
+            force,
+            clobber,
+        ) {
+            Ok(modifications) => Response::Load { dir, modifications },
+            Err(err) => Response::Error {
+                message: err.to_string(),
+            },
+        },
+        Request::Subscribe { dir, env } => {
+            let env = env.unwrap_or_else(|| env::vars().collect());
+            let profile = loader::selected_profile(&env).map(str::to_string);
+            let fingerprint = fingerprint(&dir, profile.as_deref(), trust_mode());
+            subscriptions.lock().unwrap().push(Subscription {
+                fingerprint,
+                dir: dir.clone(),
+                profile,
+                writer: writer.clone(),
+            });
+            if let Some(wake) = WAKE.get() {
+                let _ = wake.send(());
+            }
+            Response::Subscribed { dir }
+        }
+        Request::Unsubscribe { dir } => {
+            subscriptions
+                .lock()
+                .unwrap()
+                .retain(|sub| !(sub.dir == dir && Arc::ptr_eq(&sub.writer, writer)));
+            Response::Unsubscribed { dir }
+        }
+        Request::Ping => Response::Pong,
+    }
+}
+
+fn load(
+    dir: &Path,
+    env: HashMap<String, String>,
+    force: bool,
+    clobber: bool,
+) -> Result<Modifications, Error> {
+    if hook::is_disabled(&env) {
+        return Ok(Modifications::new(HashMap::new(), vec![]));
+    }
+    let data = env.get("__shadowenv_data").cloned().unwrap_or_default();
+    // Read for every request, so that changes apply without restarting the daemon.
+    let config = Config::load()?;
+
+    let _guard = EVAL_LOCK.lock().unwrap();
+    match hook::load_env(dir.to_path_buf(), env, data, force, clobber, &config)? {
+        Some((shadowenv, _)) => Modifications::from_shadowenv(&shadowenv),
+        None => Ok(Modifications::new(HashMap::new(), vec![])),
+    }
+}
+
+/// Computes the current state of the shadowenv for `dir`. Returns None if it can't be determined,
+/// e.g. because of an invalid parent link. `profile` is the one selected by the client.
+fn fingerprint(dir: &Path, profile: Option<&str>, trust: TrustMode) -> Option<Fingerprint> {
+    let roots = loader::find_shadowenv_paths(dir).ok()?;
+    if roots.is_empty() {
+        return Some(Fingerprint {
+            hash: None,
+            trusted: true,
+        });
+    }
+    let trusted = trust == TrustMode::Disabled || trust::ensure_dir_tree_trusted(&roots).is_ok();
+    let sources = hook::load_sources(roots, profile).ok()?;
+
+    Some(Fingerprint {
+        hash: sources.and_then(|sources| sources.hash()),
+        trusted,
+    })
+}
+
+fn trust_mode() -> TrustMode {
+    Config::load().map_or(TrustMode::Signed, |config| config.trust())
+}
+
+/// Watch what can change the fingerprints of the subscriptions, the same way `shadowenv watch`
+/// does, and tell subscribers about changes. Watched paths are updated as subscriptions come and
+/// go.
+fn watch_subscriptions(
+    subscriptions: &Subscriptions,
+    mut watcher: RecommendedWatcher,
+    rx: Receiver<()>,
+) -> Result<(), Error> {
+    let mut watched = HashSet::new();
+    loop {
+        watch::wait_for_changes(&rx)?;
+        notify_changes(subscriptions);
+
+        let dirs: Vec<(PathBuf, Option<String>)> = subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|sub| (sub.dir.clone(), sub.profile.clone()))
+            .collect();
+        // The user config, for its trust mode, and the trust key.
+        let mut wanted: HashSet<PathBuf> = Config::path()
+            .ok()
+            .and_then(|path| Some(path.parent()?.to_path_buf()))
+            .into_iter()
+            .collect();
+        for (dir, profile) in dirs {
+            let roots = loader::find_shadowenv_paths(&dir).unwrap_or_default();
+            wanted.extend(watch::watch_paths(&dir, &roots, profile.as_deref()));
+        }
+        watch::update_watches(&mut watcher, &mut watched, wanted);
+    }
+}
+
+/// Tell subscribers about the dirs whose fingerprint changed. Fingerprints are computed and sent
+/// without holding the lock on the subscriptions: both can take a while.
+fn notify_changes(subscriptions: &Subscriptions) {
+    let snapshot: Vec<(PathBuf, Option<String>, Option<Fingerprint>, Writer)> = subscriptions
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|sub| {
+            (
+                sub.dir.clone(),
+                sub.profile.clone(),
+                sub.fingerprint.clone(),
+                sub.writer.clone(),
+            )
+        })
+        .collect();
+
+    // Read once for all of them, so that they agree.
+    let trust = trust_mode();
+    let mut changed = vec![];
+    let mut gone: Vec<Writer> = vec![];
+    for (dir, profile, previous, writer) in snapshot {
+        let current = fingerprint(&dir, profile.as_deref(), trust);
+        if current == previous {
+            continue;
+        }
+
+        let shown = current.clone().unwrap_or(Fingerprint {
+            hash: None,
+            trusted: false,
+        });
+        let event = Response::Changed {
+            dir: dir.clone(),
+            hash: shown.hash.map(|hash| format!("{:016x}", hash)),
+            trusted: shown.trusted,
+        };
+        if send(&writer, &event).is_err() {
+            gone.push(writer.clone());
+        }
+        changed.push((dir, current, writer));
+    }
+
+    let mut subscriptions = subscriptions.lock().unwrap();
+    // Drop subscriptions of clients that went away.
+    subscriptions.retain(|sub| !gone.iter().any(|writer| Arc::ptr_eq(&sub.writer, writer)));
+    for sub in subscriptions.iter_mut() {
+        if let Some((_, current, _)) = changed
+            .iter()
+            .find(|(dir, _, writer)| *dir == sub.dir && Arc::ptr_eq(&sub.writer, writer))
+        {
+            sub.fingerprint = current.clone();
+        }
+    }
+}
+
+fn send(writer: &Writer, response: &Response) -> Result<(), Error> {
+    let mut line = serde_json::to_string(response)?;
+    line.push('\n');
+    writer.lock().unwrap().write_all(line.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    // The client end of the socket pair has to stay alive, or notifications fail to send.
+    fn setup() -> (Writer, Subscriptions, UnixStream) {
+        let (stream, client) = UnixStream::pair().unwrap();
+        (
+            Arc::new(Mutex::new(stream)),
+            Arc::new(Mutex::new(vec![])),
+            client,
+        )
+    }
+
+    fn request(json: &str) -> Request {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn ping() {
+        let (writer, subscriptions, _client) = setup();
+        let response = handle(request(r#"{"cmd":"ping"}"#), &writer, &subscriptions);
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"type":"pong"}"#
+        );
+    }
+
+    #[test]
+    fn load_without_shadowenv_has_no_modifications() {
+        let (writer, subscriptions, _client) = setup();
+        let temp_dir = tempdir().unwrap();
+        let json = format!(
+            r#"{{"cmd":"load","dir":{:?},"env":{{"PATH":"/bin"}}}}"#,
+            temp_dir.path()
+        );
+
+        let response = handle(request(&json), &writer, &subscriptions);
+        match response {
+            Response::Load { modifications, .. } => {
+                assert!(modifications.exported.is_empty())
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_untrusted_shadowenv_is_an_error() {
+        let (writer, subscriptions, _client) = setup();
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir(temp_dir.path().join(".shadowenv.d")).unwrap();
+        let json = format!(r#"{{"cmd":"load","dir":{:?},"env":{{}}}}"#, temp_dir.path());
+
+        let response = handle(request(&json), &writer, &subscriptions);
+        match response {
+            Response::Error { message } => assert!(message.contains("untrusted")),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_in_deactivated_shell_has_no_modifications() {
+        let (writer, subscriptions, _client) = setup();
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir(temp_dir.path().join(".shadowenv.d")).unwrap();
+        let json = format!(
+            r#"{{"cmd":"load","dir":{:?},"env":{{"__shadowenv_disabled":"1"}}}}"#,
+            temp_dir.path()
+        );
+
+        let response = handle(request(&json), &writer, &subscriptions);
+        match response {
+            Response::Load { modifications, .. } => {
+                assert!(modifications.exported.is_empty())
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn subscriptions_use_the_client_profile() {
+        let (writer, subscriptions, _client) = setup();
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        fs::create_dir(dir.join(".shadowenv.d")).unwrap();
+        fs::write(dir.join(".shadowenv.d/a.lisp"), "(env/set \"A\" \"1\")").unwrap();
+
+        let subscribe = |env: &str| {
+            let json = format!(r#"{{"cmd":"subscribe","dir":{:?},"env":{}}}"#, dir, env);
+            handle(request(&json), &writer, &subscriptions);
+        };
+        subscribe("{}");
+        subscribe(r#"{"SHADOWENV_PROFILE":"ci"}"#);
+
+        let subscriptions = subscriptions.lock().unwrap();
+        assert_eq!(subscriptions[1].profile.as_deref(), Some("ci"));
+        let hash = |i: usize| subscriptions[i].fingerprint.clone().unwrap().hash;
+        assert_ne!(hash(0), hash(1));
+    }
+
+    #[test]
+    fn relative_dirs_are_rejected() {
+        let (writer, subscriptions, _client) = setup();
+        for json in [
+            r#"{"cmd":"load","dir":"project","env":{}}"#,
+            r#"{"cmd":"subscribe","dir":"../project"}"#,
+        ] {
+            match handle(request(json), &writer, &subscriptions) {
+                Response::Error { message } => assert!(message.contains("absolute")),
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+        assert!(subscriptions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn subscriptions_track_fingerprint_changes() {
+        let (writer, subscriptions, _client) = setup();
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let json = format!(r#"{{"cmd":"subscribe","dir":{:?}}}"#, dir);
+
+        handle(request(&json), &writer, &subscriptions);
+        assert_eq!(subscriptions.lock().unwrap().len(), 1);
+
+        fs::create_dir(dir.join(".shadowenv.d")).unwrap();
+        fs::write(dir.join(".shadowenv.d/a.lisp"), "(env/set \"A\" \"1\")").unwrap();
+        notify_changes(&subscriptions);
+        let fingerprint = subscriptions.lock().unwrap()[0]
+            .fingerprint
+            .clone()
+            .unwrap();
+        assert!(fingerprint.hash.is_some());
+        assert!(!fingerprint.trusted);
+
+        let json = format!(r#"{{"cmd":"unsubscribe","dir":{:?}}}"#, dir);
+        handle(request(&json), &writer, &subscriptions);
+        assert!(subscriptions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn subscriptions_of_clients_that_hung_up_are_dropped() {
+        let (writer, subscriptions, client) = setup();
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let json = format!(r#"{{"cmd":"subscribe","dir":{:?}}}"#, dir);
+        handle(request(&json), &writer, &subscriptions);
+
+        drop(client);
+        fs::create_dir(dir.join(".shadowenv.d")).unwrap();
+        notify_changes(&subscriptions);
+        assert!(subscriptions.lock().unwrap().is_empty());
+    }
+}
@@ -1,5 +1,18 @@
-use crate::{cli::DiffCmd, shadowenv::Shadowenv, undo};
-use std::{collections::BTreeMap, env};
+use crate::{
+    cli::{DiffCmd, DiffFormat, PathsView},
+    config::Config,
+    hook,
+    secrets::{self, SecretMatcher},
+    shadowenv::{self, Shadowenv},
+    undo,
+};
+use anyhow::Error;
+use serde_derive::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    path::PathBuf,
+};
 
 trait Logger {
     fn print(&mut self, value: String);
@@ -13,31 +26,142 @@ impl Logger for StdoutLogger {
     }
 }
 
+/// A variable to show in the diff, in the order it should be shown.
+enum Change<'a> {
+    Scalar(&'a undo::Scalar),
+    /// A pathlist, along with its current value.
+    List(&'a undo::List, String),
+    /// A variable shadowenv didn't touch, only shown in verbose mode.
+    Unchanged(String, String),
+}
+
+#[derive(Serialize)]
+struct JsonDiff<'a> {
+    scalars: Vec<&'a undo::Scalar>,
+    lists: Vec<JsonList<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unchanged: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Serialize)]
+struct JsonList<'a> {
+    #[serde(flatten)]
+    list: &'a undo::List,
+    current: Option<String>,
+}
+
+/// How to print a diff.
+struct Options {
+    verbose: bool,
+    color: bool,
+    format: DiffFormat,
+    paths: PathsView,
+    /// Variables whose values are redacted, on top of those set with `env/set-secret`.
+    secrets: SecretMatcher,
+}
+
 /// Print a diff of the env.
-pub fn run(cmd: DiffCmd) {
-    let color = !cmd.no_color;
+pub fn run(cmd: DiffCmd) -> Result<(), Error> {
+    let options = Options {
+        verbose: cmd.verbose,
+        color: !cmd.no_color,
+        format: cmd.format,
+        paths: cmd.paths,
+        secrets: Config::load()?.secret_matcher(),
+    };
     let data = Shadowenv::from_env();
 
-    run_with_logger(
-        &mut StdoutLogger {},
-        env::vars().collect(),
-        cmd.verbose,
-        color,
-        data,
-    )
+    if let (Some(from_dir), Some(to_dir)) = (cmd.from, cmd.to) {
+        let env_vars: HashMap<String, String> = env::vars().collect();
+        let from = evaluate(PathBuf::from(&from_dir), env_vars.clone(), data.clone())?;
+        let to = evaluate(PathBuf::from(&to_dir), env_vars, data)?;
+
+        let mut to_vars: Vec<(String, String)> = to.env().clone().into_iter().collect();
+        to_vars.sort();
+        print_diff(
+            &mut StdoutLogger {},
+            &Shadowenv::compare(&from, &to),
+            to_vars,
+            &options,
+            (&from_dir, &to_dir),
+        );
+        return Ok(());
+    }
+
+    run_with_logger(&mut StdoutLogger {}, env::vars().collect(), &options, data)
+}
+
+/// The environment we'd end up with in `dir`, starting from the current env with the active
+/// shadowenv (if any) undone.
+fn evaluate(
+    dir: PathBuf,
+    env_vars: HashMap<String, String>,
+    shadowenv_data: String,
+) -> Result<Shadowenv, Error> {
+    let config = Config::load()?;
+    match hook::load_env(dir, env_vars.clone(), shadowenv_data, true, false, &config)? {
+        Some((shadowenv, _)) => Ok(shadowenv),
+        // Nothing active, nothing to activate: the env is what it is.
+        None => Ok(Shadowenv::new(env_vars, undo::Data::new(), 0, false)),
+    }
 }
 
 fn run_with_logger(
     logger: &mut dyn Logger,
     env_vars: Vec<(String, String)>,
-    verbose: bool,
-    color: bool,
+    options: &Options,
     shadowenv_data: String,
+) -> Result<(), Error> {
+    let (_, shadowenv_data) = undo::parse(&shadowenv_data)?;
+
+    print_diff(
+        logger,
+        &shadowenv_data,
+        env_vars,
+        options,
+        ("unshadowed", "shadowenv"),
+    );
+    Ok(())
+}
+
+fn print_diff(
+    logger: &mut dyn Logger,
+    shadowenv_data: &undo::Data,
+    env_vars: Vec<(String, String)>,
+    options: &Options,
+    labels: (&str, &str),
 ) {
-    let mut parts = shadowenv_data.splitn(2, ':');
-    let _prev_hash = parts.next();
-    let json_data = parts.next().unwrap_or("{}");
-    let shadowenv_data = undo::Data::from_str(json_data).unwrap();
+    let mut shadowenv_data = shadowenv_data.clone();
+    restore_hashed_values(&mut shadowenv_data, &env_vars);
+    secrets::redact_data(&mut shadowenv_data, &options.secrets);
+    let changes = changes(&shadowenv_data, env_vars, options);
+
+    match options.format {
+        DiffFormat::Text => print_text(logger, changes, options.color, options.paths),
+        DiffFormat::Json => print_json(logger, changes),
+        DiffFormat::Unified => print_unified(logger, changes, options.color, labels),
+    }
+}
+
+/// Only the hash of some current values is kept: take them from the env, if they still match.
+fn restore_hashed_values(shadowenv_data: &mut undo::Data, env_vars: &[(String, String)]) {
+    for scalar in shadowenv_data.scalars.iter_mut() {
+        if let Some(hash) = &scalar.current_hash {
+            scalar.current = env_vars
+                .iter()
+                .find(|(name, value)| *name == scalar.name && secrets::hash(value) == *hash)
+                .map(|(_, value)| value.clone());
+        }
+    }
+}
+
+/// Pair up the undo data with the current env: variables appear in the order of the env, followed
+/// by those shadowenv unset.
+fn changes<'a>(
+    shadowenv_data: &'a undo::Data,
+    env_vars: Vec<(String, String)>,
+    options: &Options,
+) -> Vec<Change<'a>> {
     let mut scalars = shadowenv_data
         .scalars
         .iter()
@@ -50,23 +174,120 @@ fn run_with_logger(
         .map(|s| (s.name.clone(), s))
         .collect::<BTreeMap<_, _>>();
 
+    let mut changes = vec![];
     for (name, value) in env_vars {
         if let Some(scalar) = scalars.remove(&name) {
-            diff_scalar(logger, scalar, color)
+            changes.push(Change::Scalar(scalar))
         } else if let Some(list) = lists.remove(&name) {
-            diff_list(logger, list, &value, color)
-        } else if verbose {
-            print_verbose(logger, &name, &value)
+            changes.push(Change::List(list, value))
+        } else if options.verbose {
+            let value = if options.secrets.matches(&name) {
+                secrets::redact(&value)
+            } else {
+                value
+            };
+            changes.push(Change::Unchanged(name, value))
         }
     }
 
-    scalars
-        .iter()
-        .for_each(|(_name, scalar)| diff_scalar(logger, scalar, color));
+    changes.extend(scalars.into_values().map(Change::Scalar));
+    changes.extend(
+        lists
+            .into_values()
+            .map(|list| Change::List(list, "".to_string())),
+    );
+    changes
+}
 
-    lists
-        .iter()
-        .for_each(|(_name, list)| diff_list(logger, list, "", color));
+fn print_text(logger: &mut dyn Logger, changes: Vec<Change>, color: bool, paths: PathsView) {
+    for change in changes {
+        match change {
+            Change::Scalar(scalar) => diff_scalar(logger, scalar, color),
+            Change::List(list, current) => match paths {
+                PathsView::Full => diff_list(logger, list, &current, color),
+                PathsView::Compact => diff_list_compact(logger, list, &current, color),
+                PathsView::Columns => diff_list_columns(logger, list, &current, color),
+            },
+            Change::Unchanged(name, value) => print_verbose(logger, &name, &value),
+        }
+    }
+}
+
+fn print_json(logger: &mut dyn Logger, changes: Vec<Change>) {
+    let mut diff = JsonDiff {
+        scalars: vec![],
+        lists: vec![],
+        unchanged: None,
+    };
+    for change in changes {
+        match change {
+            Change::Scalar(scalar) => diff.scalars.push(scalar),
+            Change::List(list, current) => diff.lists.push(JsonList {
+                list,
+                current: Some(current).filter(|c| !c.is_empty()),
+            }),
+            Change::Unchanged(name, value) => {
+                diff.unchanged
+                    .get_or_insert_with(BTreeMap::new)
+                    .insert(name, value);
+            }
+        }
+    }
+    diff.scalars.sort_by(|a, b| a.name.cmp(&b.name));
+    diff.lists.sort_by(|a, b| a.list.name.cmp(&b.list.name));
+
+    logger.print(serde_json::to_string(&diff).unwrap());
+}
+
+/// Print the diff like a patch: a hunk per variable, with pathlists split into one line per entry.
+/// Unchanged variables (in verbose mode) are shown as context.
+fn print_unified(
+    logger: &mut dyn Logger,
+    changes: Vec<Change>,
+    color: bool,
+    (from, to): (&str, &str),
+) {
+    logger.print(format!("--- {}", from));
+    logger.print(format!("+++ {}", to));
+
+    for change in changes {
+        match change {
+            Change::Scalar(scalar) => {
+                logger.print(format!("@@ {} @@", scalar.name));
+                if let Some(value) = &scalar.original {
+                    patch_line(logger, '-', &format!("{}={}", scalar.name, value), color);
+                }
+                if let Some(value) = &scalar.current {
+                    patch_line(logger, '+', &format!("{}={}", scalar.name, value), color);
+                }
+            }
+            Change::List(list, current) => {
+                logger.print(format!("@@ {} @@", list.name));
+                // Unshadowing puts deleted entries back at the front of the list.
+                for entry in &list.deletions {
+                    patch_line(logger, '-', entry, color);
+                }
+                for entry in current.split(':').filter(|x| !x.is_empty()) {
+                    if list.additions.contains(&entry.to_string()) {
+                        patch_line(logger, '+', entry, color);
+                    } else {
+                        patch_line(logger, ' ', entry, color);
+                    }
+                }
+            }
+            Change::Unchanged(name, value) => {
+                patch_line(logger, ' ', &format!("{}={}", name, value), color)
+            }
+        }
+    }
+}
+
+fn patch_line(logger: &mut dyn Logger, sign: char, line: &str, color: bool) {
+    match (sign, color) {
+        ('+', true) => logger.print(format!("\x1b[92m+{}\x1b[0m\x1b[K", line)),
+        ('-', true) => logger.print(format!("\x1b[91m-{}\x1b[0m\x1b[K", line)),
+        _ => logger.print(format!("{}{}", sign, line)),
+    }
 }
 
 fn diff_list(logger: &mut dyn Logger, list: &undo::List, current: &str, color: bool) {
@@ -102,6 +323,77 @@ fn diff_list(logger: &mut dyn Logger, list: &undo::List, current: &str, color: b
     diff_add(logger, &list.name, &newline, color);
 }
 
+/// The entries of `list` that changed, with their positions: additions in the current value,
+/// deletions in the value unshadowing would restore. Additions come first.
+fn changed_entries<'a>(list: &'a undo::List, current: &str) -> Vec<(char, usize, &'a str)> {
+    let unshadowed = shadowenv::unshadow_pathlist(Some(current).filter(|c| !c.is_empty()), list)
+        .unwrap_or_default();
+    let position = |value: &str, entry: &str| value.split(':').position(|x| x == entry);
+
+    let mut additions: Vec<_> = list
+        .additions
+        .iter()
+        .filter_map(|entry| Some(('+', position(current, entry)?, entry.as_str())))
+        .collect();
+    let mut deletions: Vec<_> = list
+        .deletions
+        .iter()
+        .filter_map(|entry| Some(('-', position(&unshadowed, entry)?, entry.as_str())))
+        .collect();
+    additions.sort();
+    deletions.sort();
+
+    additions.extend(deletions);
+    additions
+}
+
+fn colorize_entry(sign: char, text: String, color: bool) -> String {
+    match (sign, color) {
+        ('+', true) => format!("\x1b[92m{}\x1b[0m", text),
+        ('-', true) => format!("\x1b[91m{}\x1b[0m", text),
+        _ => text,
+    }
+}
+
+fn diff_list_compact(logger: &mut dyn Logger, list: &undo::List, current: &str, color: bool) {
+    let entries: Vec<String> = changed_entries(list, current)
+        .into_iter()
+        .map(|(sign, index, entry)| {
+            colorize_entry(sign, format!("{}[{}] {}", sign, index, entry), color)
+        })
+        .collect();
+    if !entries.is_empty() {
+        logger.print(format!("{} {}", list.name, entries.join("  ")));
+    }
+}
+
+fn diff_list_columns(logger: &mut dyn Logger, list: &undo::List, current: &str, color: bool) {
+    let entries = changed_entries(list, current);
+    let index_width = entries
+        .iter()
+        .map(|(_, index, _)| index.to_string().len())
+        .max()
+        .unwrap_or(0);
+
+    for (i, (sign, index, entry)) in entries.into_iter().enumerate() {
+        // Only the first line is labelled with the variable name.
+        let name = if i == 0 { list.name.as_str() } else { "" };
+        let line = format!(
+            "{} [{:>width$}] {}",
+            sign,
+            index,
+            entry,
+            width = index_width
+        );
+        logger.print(format!(
+            "{:name_width$}  {}",
+            name,
+            colorize_entry(sign, line, color),
+            name_width = list.name.len()
+        ));
+    }
+}
+
 fn diff_scalar(logger: &mut dyn Logger, scalar: &undo::Scalar, color: bool) {
     if let Some(value) = &scalar.original {
         diff_remove(logger, &scalar.name, value, color);
@@ -148,6 +440,81 @@ mod tests {
         }
     }
 
+    fn options(verbose: bool, format: DiffFormat, paths: PathsView) -> Options {
+        Options {
+            verbose,
+            color: false,
+            format,
+            paths,
+            secrets: SecretMatcher::default(),
+        }
+    }
+
+    #[test]
+    fn hashed_values_test() {
+        let mut logger = DummyLogger::default();
+
+        let env_vars = vec![("RAILS_ENV".to_string(), "test".to_string())];
+        let data = format!(
+            r#"0000000000000001:{{"scalars":[{{"name":"RAILS_ENV","original":"development","current":null,"no_clobber":false,"current_hash":"{}"}}],"lists":[]}}"#,
+            secrets::hash("test")
+        );
+        run_with_logger(
+            &mut logger,
+            env_vars,
+            &options(false, DiffFormat::Text, PathsView::Full),
+            data,
+        )
+        .unwrap();
+
+        assert_eq!(
+            logger.0,
+            vec!["- RAILS_ENV=development", "+ RAILS_ENV=test"]
+        );
+    }
+
+    #[test]
+    fn corrupt_data_test() {
+        let mut logger = DummyLogger::default();
+
+        let data = r#"0000000000000001:{"scalars":[{"name":"RAILS_E"#;
+        let err = run_with_logger(
+            &mut logger,
+            vec![],
+            &options(false, DiffFormat::Text, PathsView::Full),
+            data.to_string(),
+        )
+        .unwrap_err();
+
+        assert!(err.is::<undo::CorruptData>());
+        assert!(logger.0.is_empty());
+    }
+
+    #[test]
+    fn redacted_test() {
+        let mut logger = DummyLogger::default();
+
+        let env_vars = vec![
+            ("API_TOKEN".to_string(), "t0ken".to_string()),
+            ("DB_PASSWORD".to_string(), "hunter2".to_string()),
+            ("HOME".to_string(), "/home/user".to_string()),
+        ];
+        let data = r#"0000000000000001:{"scalars":[{"name":"DB_PASSWORD","original":"old","current":"hunter2","no_clobber":false,"secret":true}],"lists":[]}"#;
+        let mut options = options(true, DiffFormat::Text, PathsView::Full);
+        options.secrets = SecretMatcher::new(&["*_TOKEN".to_string()]);
+        run_with_logger(&mut logger, env_vars, &options, data.to_string()).unwrap();
+
+        assert_eq!(
+            logger.0,
+            vec![
+                format!("  API_TOKEN={}", secrets::redact("t0ken")),
+                format!("- DB_PASSWORD={}", secrets::redact("old")),
+                format!("+ DB_PASSWORD={}", secrets::redact("hunter2")),
+                "  HOME=/home/user".to_string(),
+            ]
+        );
+    }
+
     #[test]
     fn nominal_test() {
         let mut logger = DummyLogger::default();
@@ -159,7 +526,13 @@ mod tests {
         ];
 
         let data = r#"62b0b9f86cda84d4:{"scalars":[],"lists":[{"name":"VAR_C","additions":["/added"],"deletions":["/removed"]},{"name":"VAR_B","additions":["/added"],"deletions":[]},{"name":"VAR_A","additions":["/added"],"deletions":[]}]}"#;
-        run_with_logger(&mut logger, env_vars, false, false, data.to_string());
+        run_with_logger(
+            &mut logger,
+            env_vars,
+            &options(false, DiffFormat::Text, PathsView::Full),
+            data.to_string(),
+        )
+        .unwrap();
 
         let expected: Vec<_> = [
             "- VAR_A=/existent",
@@ -175,4 +548,100 @@ mod tests {
 
         assert_eq!(logger.0, expected);
     }
+
+    #[test]
+    fn json_test() {
+        let mut logger = DummyLogger::default();
+
+        let env_vars = vec![
+            ("VAR_A".to_string(), "/added:/existent".to_string()),
+            ("VAR_B".to_string(), "b".to_string()),
+            ("VAR_C".to_string(), "c".to_string()),
+        ];
+
+        let data = r#"62b0b9f86cda84d4:{"scalars":[{"name":"VAR_B","original":"a","current":"b","no_clobber":false},{"name":"VAR_D","original":"d","current":null,"no_clobber":true}],"lists":[{"name":"VAR_A","additions":["/added"],"deletions":["/removed"]}]}"#;
+        run_with_logger(
+            &mut logger,
+            env_vars,
+            &options(false, DiffFormat::Json, PathsView::Full),
+            data.to_string(),
+        )
+        .unwrap();
+
+        let expected = r#"{"scalars":[{"name":"VAR_B","original":"a","current":"b","no_clobber":false},{"name":"VAR_D","original":"d","current":null,"no_clobber":true}],"lists":[{"name":"VAR_A","additions":["/added"],"deletions":["/removed"],"current":"/added:/existent"}]}"#;
+        assert_eq!(logger.0, vec![expected.to_string()]);
+    }
+
+    #[test]
+    fn unified_test() {
+        let mut logger = DummyLogger::default();
+
+        let env_vars = vec![
+            ("VAR_A".to_string(), "/added:/existent".to_string()),
+            ("VAR_B".to_string(), "b".to_string()),
+            ("VAR_C".to_string(), "c".to_string()),
+        ];
+
+        let data = r#"62b0b9f86cda84d4:{"scalars":[{"name":"VAR_B","original":"a","current":"b","no_clobber":false}],"lists":[{"name":"VAR_A","additions":["/added"],"deletions":["/removed"]}]}"#;
+        run_with_logger(
+            &mut logger,
+            env_vars,
+            &options(true, DiffFormat::Unified, PathsView::Full),
+            data.to_string(),
+        )
+        .unwrap();
+
+        let expected: Vec<_> = [
+            "--- unshadowed",
+            "+++ shadowenv",
+            "@@ VAR_A @@",
+            "-/removed",
+            "+/added",
+            " /existent",
+            "@@ VAR_B @@",
+            "-VAR_B=a",
+            "+VAR_B=b",
+            " VAR_C=c",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+        assert_eq!(logger.0, expected);
+    }
+
+    #[test]
+    fn compact_paths_test() {
+        let env_vars = vec![("PATH".to_string(), "/proj/bin:/usr/bin:/bin".to_string())];
+        let data = r#"62b0b9f86cda84d4:{"scalars":[],"lists":[{"name":"PATH","additions":["/proj/bin"],"deletions":["/usr/local/bin"]}]}"#;
+
+        let mut logger = DummyLogger::default();
+        run_with_logger(
+            &mut logger,
+            env_vars.clone(),
+            &options(false, DiffFormat::Text, PathsView::Compact),
+            data.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            logger.0,
+            vec!["PATH +[0] /proj/bin  -[0] /usr/local/bin".to_string()]
+        );
+
+        let mut logger = DummyLogger::default();
+        run_with_logger(
+            &mut logger,
+            env_vars,
+            &options(false, DiffFormat::Text, PathsView::Columns),
+            data.to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            logger.0,
+            vec![
+                "PATH  + [0] /proj/bin".to_string(),
+                "      - [0] /usr/local/bin".to_string(),
+            ]
+        );
+    }
 }
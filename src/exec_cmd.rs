@@ -1,17 +1,78 @@
-use crate::{cli::ExecCmd, get_current_dir_or_exit, hook, shadowenv::Shadowenv};
-use anyhow::Error;
-use std::{iter, path::PathBuf};
+use crate::{
+    cli::ExecCmd,
+    config::Config,
+    get_current_dir_or_exit,
+    hook::{self, Modifications, VariableOutputMode},
+    shadowenv::Shadowenv,
+    undo,
+};
+use anyhow::{anyhow, Error};
+use std::{
+    collections::HashMap, env, fs, iter, os::unix::process::CommandExt, path::PathBuf,
+    process::Command,
+};
 
-/// Execute the provided command (argv) after loading the environment from the current directory
+const SHADOWENV_DATA_KEY: &str = "__shadowenv_data";
+
+/// The variables kept by `--clear-env`.
+const BASE_ENV: &[&str] = &[
+    "HOME", "USER", "LOGNAME", "PATH", "SHELL", "TERM", "LANG", "TMPDIR",
+];
+
+/// Execute the provided command (argv) after loading the environment from the current directory.
+///
+/// The child's environment is computed separately and passed to it explicitly: our own is left
+/// alone.
 pub fn run(cmd: ExecCmd) -> Result<(), Error> {
-    let data = Shadowenv::from_env();
     let pathbuf = cmd
         .dir
         .map(PathBuf::from)
         .unwrap_or(get_current_dir_or_exit());
 
-    if let Some(shadowenv) = hook::load_env(pathbuf, data, true, false)? {
-        hook::mutate_own_env(&shadowenv)?;
+    let mut overrides = vec![];
+    for path in &cmd.env_file {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| anyhow!("unable to read env file {}: {}", path, err))?;
+        overrides.extend(parse_env_file(&contents).map_err(|err| anyhow!("{}: {}", path, err))?);
+    }
+    overrides.extend(cmd.env);
+
+    let mut vars: HashMap<String, String> = env::vars().collect();
+    if cmd.clear_env || cmd.no_shadowenv {
+        vars = unshadow(vars, cmd.clobber)?;
+    }
+    if cmd.clear_env {
+        vars.retain(|key, _| BASE_ENV.contains(&key.as_str()));
+    }
+    vars.extend(overrides);
+
+    // Like the hook, don't load anything in a shell where `shadowenv deactivate` was run.
+    if !cmd.no_shadowenv && !hook::is_disabled(&vars) {
+        let data = vars.get(SHADOWENV_DATA_KEY).cloned().unwrap_or_default();
+        let config = Config::load()?;
+        if let Some((shadowenv, _)) =
+            hook::load_env(pathbuf, vars.clone(), data, true, cmd.clobber, &config)?
+        {
+            for (key, value) in shadowenv.exports()? {
+                match value {
+                    Some(value) => vars.insert(key, value),
+                    None => vars.remove(&key),
+                };
+            }
+            vars.retain(|key, _| !shadowenv.no_propagate().contains(key));
+        }
+    }
+    if cmd.strip_metadata {
+        vars.remove(SHADOWENV_DATA_KEY);
+    }
+
+    if cmd.print {
+        let vars = vars.into_iter().map(|(key, value)| (key, Some(value)));
+        hook::print_modifications(
+            &Modifications::new(vars.collect(), vec![]),
+            &VariableOutputMode::from_options(&cmd.format),
+        );
+        return Ok(());
     }
 
     let argv = if let Some(argv0) = cmd.cmd_argv0 {
@@ -23,6 +84,107 @@ pub fn run(cmd: ExecCmd) -> Result<(), Error> {
     };
 
     // exec only returns if it was unable to start the new process, and it's always an error.
-    let err = exec::Command::new(&argv[0]).args(&argv[1..]).exec();
+    let err = Command::new(&argv[0])
+        .args(&argv[1..])
+        .env_clear()
+        .envs(&vars)
+        .exec();
     Err(err.into())
 }
+
+/// `vars` with the changes of the active shadowenv undone.
+fn unshadow(
+    vars: HashMap<String, String>,
+    clobber: bool,
+) -> Result<HashMap<String, String>, Error> {
+    let data = vars.get(SHADOWENV_DATA_KEY).cloned().unwrap_or_default();
+    let (_, data) = undo::parse(&data)?;
+
+    let mut vars = Shadowenv::new(vars, data, 0, clobber).env().clone();
+    vars.remove(SHADOWENV_DATA_KEY);
+    Ok(vars)
+}
+
+/// Parse `KEY=VALUE` lines, ignoring blank lines and `#` comments. Values are kept as is, spaces
+/// included, except for one pair of matching quotes around them.
+fn parse_env_file(contents: &str) -> Result<Vec<(String, String)>, Error> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim_start()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                Ok((key.trim().to_string(), unquote(value).to_string()))
+            }
+            _ => Err(anyhow!("line {}: expected KEY=VALUE", number)),
+        })
+        .collect()
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote) {
+            return &value[1..value.len() - 1];
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unshadow() {
+        let data = r#"0000000000000001:{"scalars":[{"name":"A","original":"old","current":"new","no_clobber":false}],"lists":[{"name":"PATH","additions":["/shadow/bin"],"deletions":[]}],"prev_dirs":[]}"#;
+        let vars = HashMap::from([
+            ("A".to_string(), "new".to_string()),
+            ("PATH".to_string(), "/shadow/bin:/bin".to_string()),
+            (SHADOWENV_DATA_KEY.to_string(), data.to_string()),
+        ]);
+
+        let vars = unshadow(vars, false).unwrap();
+        assert_eq!(
+            vars,
+            HashMap::from([
+                ("A".to_string(), "old".to_string()),
+                ("PATH".to_string(), "/bin".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let vars = parse_env_file("# comment\nA=1\n\n  B =two=2\nC=\n").unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "two=2".to_string()),
+                ("C".to_string(), String::new()),
+            ]
+        );
+
+        let err = parse_env_file("A=1\nnope\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: expected KEY=VALUE");
+    }
+
+    #[test]
+    fn test_parse_env_file_quotes_and_spaces() {
+        let vars =
+            parse_env_file("A=\"a b\"\nB=' padded '\nC= spaced \nD=\"mismatched'\nE=\"\nF=\"\"\n")
+                .unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                ("A".to_string(), "a b".to_string()),
+                ("B".to_string(), " padded ".to_string()),
+                ("C".to_string(), " spaced ".to_string()),
+                ("D".to_string(), "\"mismatched'".to_string()),
+                ("E".to_string(), "\"".to_string()),
+                ("F".to_string(), String::new()),
+            ]
+        );
+    }
+}
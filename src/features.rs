@@ -1,6 +1,10 @@
-use std::fmt;
+use crate::{cli::FeaturesCmd, shadowenv::Shadowenv, undo};
+use anyhow::Error;
+use serde_derive::{Deserialize, Serialize};
+use std::{cmp::Ordering, fmt};
+use thiserror::Error as ThisError;
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Feature {
     name: String,
     version: Option<String>,
@@ -10,6 +14,14 @@ impl Feature {
     pub fn new(name: String, version: Option<String>) -> Self {
         Feature { name, version }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
 }
 
 impl fmt::Display for Feature {
@@ -20,3 +32,279 @@ impl fmt::Display for Feature {
         }
     }
 }
+
+#[derive(ThisError, Debug)]
+pub enum FeatureError {
+    #[error("`{name}` is required{}, but no shadowenv provided it", requirement_suffix(.requirement))]
+    Missing {
+        name: String,
+        requirement: Option<String>,
+    },
+
+    #[error("`{feature}` conflicts with another shadowenv, which declared it can't be used with `{name}`{}", requirement_suffix(.requirement))]
+    Conflict {
+        feature: Feature,
+        name: String,
+        requirement: Option<String>,
+    },
+
+    #[error("invalid version requirement: `{0}`")]
+    InvalidRequirement(String),
+}
+
+fn requirement_suffix(requirement: &Option<String>) -> String {
+    match requirement {
+        Some(requirement) => format!(" (version {})", requirement),
+        None => String::new(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    NotEq,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    /// `~> 3.2` means `>= 3.2, < 4`; `~> 3.2.1` means `>= 3.2.1, < 3.3`.
+    Pessimistic,
+}
+
+/// A constraint on the version of a feature, like `>= 3.2` or `> 3.0, < 4`.
+///
+/// Versions are compared component by component (`3.10 > 3.9`), missing components counting as
+/// zero. `= 3.2` (or just `3.2`) matches any version starting with those components, like `3.2.1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionReq {
+    constraints: Vec<(Op, Vec<u64>)>,
+}
+
+impl VersionReq {
+    pub fn parse(requirement: &str) -> Result<VersionReq, FeatureError> {
+        let invalid = || FeatureError::InvalidRequirement(requirement.to_string());
+
+        let mut constraints = vec![];
+        for constraint in requirement.split(',') {
+            let constraint = constraint.trim();
+            let (op, version) = [
+                ("~>", Op::Pessimistic),
+                (">=", Op::Gte),
+                ("<=", Op::Lte),
+                ("==", Op::Eq),
+                ("!=", Op::NotEq),
+                (">", Op::Gt),
+                ("<", Op::Lt),
+                ("=", Op::Eq),
+            ]
+            .iter()
+            .find_map(|(prefix, op)| constraint.strip_prefix(prefix).map(|rest| (*op, rest)))
+            .unwrap_or((Op::Eq, constraint));
+
+            let version = parse_version(version.trim()).ok_or_else(invalid)?;
+            if op == Op::Pessimistic && version.len() < 2 {
+                return Err(invalid());
+            }
+            constraints.push((op, version));
+        }
+        Ok(VersionReq { constraints })
+    }
+
+    /// Whether `version` satisfies every constraint. Versions that don't start with a number
+    /// (e.g. "stable") never do.
+    pub fn matches(&self, version: &str) -> bool {
+        let version = match parse_version(version) {
+            Some(version) => version,
+            None => return false,
+        };
+
+        self.constraints.iter().all(|(op, wanted)| match op {
+            Op::Eq => equal_up_to(&version, wanted),
+            Op::NotEq => !equal_up_to(&version, wanted),
+            Op::Gt => compare(&version, wanted) == Ordering::Greater,
+            Op::Gte => compare(&version, wanted) != Ordering::Less,
+            Op::Lt => compare(&version, wanted) == Ordering::Less,
+            Op::Lte => compare(&version, wanted) != Ordering::Greater,
+            Op::Pessimistic => {
+                let mut upper = wanted[..wanted.len() - 1].to_vec();
+                *upper.last_mut().unwrap() += 1;
+                compare(&version, wanted) != Ordering::Less
+                    && compare(&version, &upper) == Ordering::Less
+            }
+        })
+    }
+}
+
+/// Parse the leading numeric components of a version: "3.3.0-preview1" is [3, 3, 0].
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let mut components = vec![];
+    for part in version.split('.') {
+        let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+        match digits.parse() {
+            Ok(n) => components.push(n),
+            Err(_) => break,
+        }
+        // Anything after a non-numeric suffix isn't part of the version number anymore.
+        if digits.len() != part.len() {
+            break;
+        }
+    }
+    if components.is_empty() {
+        None
+    } else {
+        Some(components)
+    }
+}
+
+/// Order versions numerically, falling back to plain string order for non-numeric ones.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    compare(
+        &parse_version(a).unwrap_or_default(),
+        &parse_version(b).unwrap_or_default(),
+    )
+    .then_with(|| a.cmp(b))
+}
+
+/// Whether `version` equals `wanted` as far as `wanted` goes: "= 3.2" matches 3.2.5. Missing
+/// components are zeros, as when ordering versions, so "= 3.2.0" matches 3.2 too.
+fn equal_up_to(version: &[u64], wanted: &[u64]) -> bool {
+    let mut version = version.to_vec();
+    if version.len() < wanted.len() {
+        version.resize(wanted.len(), 0);
+    }
+    version.starts_with(wanted)
+}
+
+fn compare(a: &[u64], b: &[u64]) -> Ordering {
+    let len = a.len().max(b.len());
+    let component = |v: &[u64], i: usize| v.get(i).copied().unwrap_or(0);
+    (0..len)
+        .map(|i| component(a, i).cmp(&component(b, i)))
+        .find(|ord| *ord != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+/// A feature name, optionally constrained to some versions: what `require` and `conflicts` take.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Requirement {
+    name: String,
+    requirement: Option<String>,
+    version: Option<VersionReq>,
+}
+
+impl Requirement {
+    pub fn new(name: &str, requirement: Option<&str>) -> Result<Requirement, FeatureError> {
+        Ok(Requirement {
+            name: name.to_string(),
+            requirement: requirement.map(|s| s.to_string()),
+            version: requirement.map(VersionReq::parse).transpose()?,
+        })
+    }
+
+    /// Whether `feature` is the named one, with a version satisfying the requirement if there is
+    /// one. Features provided without a version only match requirements without one.
+    pub fn matches(&self, feature: &Feature) -> bool {
+        feature.name == self.name
+            && match &self.version {
+                None => true,
+                Some(requirement) => feature
+                    .version()
+                    .is_some_and(|version| requirement.matches(version)),
+            }
+    }
+
+    pub fn missing(&self) -> FeatureError {
+        FeatureError::Missing {
+            name: self.name.clone(),
+            requirement: self.requirement.clone(),
+        }
+    }
+
+    pub fn conflict(&self, feature: &Feature) -> FeatureError {
+        FeatureError::Conflict {
+            feature: feature.clone(),
+            name: self.name.clone(),
+            requirement: self.requirement.clone(),
+        }
+    }
+}
+
+/// List the features provided by the active shadowenv.
+pub fn run(cmd: FeaturesCmd) -> Result<(), Error> {
+    let (_, data) = undo::parse(&Shadowenv::from_env())?;
+
+    if cmd.json {
+        println!("{}", serde_json::to_string(&data.features)?);
+    } else {
+        for feature in data.features {
+            println!("{}", feature);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(requirement: &str, version: &str) -> bool {
+        VersionReq::parse(requirement).unwrap().matches(version)
+    }
+
+    #[test]
+    fn test_version_requirements() {
+        assert!(matches(">= 3.2", "3.3.0"));
+        assert!(matches(">= 3.2", "3.2"));
+        assert!(!matches(">= 3.2", "3.1.9"));
+        assert!(matches(">3.9", "3.10"));
+        assert!(matches("< 4", "3.99"));
+        assert!(!matches("<= 3.2", "3.2.1"));
+        assert!(matches("> 3.0, < 4", "3.3"));
+        assert!(!matches("> 3.0, < 4", "4.0"));
+        assert!(matches("3.2", "3.2.5"));
+        assert!(matches("= 3", "3.3.0"));
+        assert!(!matches("== 3.2", "3.20"));
+        assert!(matches("!= 3.2", "3.3"));
+        assert!(matches("= 3.2.0", "3.2"));
+        assert!(!matches("= 3.2.1", "3.2"));
+        assert!(!matches("!= 3.2.0", "3.2"));
+        assert!(matches("~> 3.2", "3.9"));
+        assert!(!matches("~> 3.2", "4.0"));
+        assert!(matches("~> 3.2.1", "3.2.5"));
+        assert!(!matches("~> 3.2.1", "3.3.0"));
+        assert!(matches(">= 3.3", "3.3.0-preview1"));
+        assert!(!matches(">= 1", "stable"));
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("3.10", "3.9"), Ordering::Greater);
+        assert_eq!(compare_versions("3.2", "3.2.0"), Ordering::Less);
+        assert_eq!(compare_versions("3.3.0", "3.3.0"), Ordering::Equal);
+        assert_eq!(compare_versions("stable", "1.0"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_requirement_matches_features() {
+        let ruby = Feature::new("ruby".to_string(), Some("3.3.0".to_string()));
+        let node = Feature::new("node".to_string(), None);
+
+        assert!(Requirement::new("ruby", None).unwrap().matches(&ruby));
+        assert!(Requirement::new("ruby", Some(">= 3.2"))
+            .unwrap()
+            .matches(&ruby));
+        assert!(!Requirement::new("ruby", Some("< 3"))
+            .unwrap()
+            .matches(&ruby));
+        assert!(!Requirement::new("ruby", None).unwrap().matches(&node));
+        assert!(Requirement::new("node", None).unwrap().matches(&node));
+        assert!(!Requirement::new("node", Some("20")).unwrap().matches(&node));
+    }
+
+    #[test]
+    fn test_invalid_requirements() {
+        assert!(VersionReq::parse(">= ").is_err());
+        assert!(VersionReq::parse("latest").is_err());
+        assert!(VersionReq::parse("~> 3").is_err());
+    }
+}
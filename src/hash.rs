@@ -24,6 +24,13 @@ pub struct SourceList {
 pub struct Source {
     pub dir: String,
     pub files: Vec<SourceFile>,
+    /// The profile selected when loading, even if this shadowenv doesn't define it: programs can
+    /// check it with `(profile)`.
+    pub profile: Option<String>,
+    /// The programs of the profile, run after `files`.
+    pub profile_files: Vec<SourceFile>,
+    /// The contents of the shadowenv's `config.toml`, which changes how it's loaded.
+    pub config: Option<String>,
 }
 
 #[derive(Debug, Clone, Eq)]
@@ -61,15 +68,25 @@ struct WrongInputSize;
 
 impl Source {
     pub fn new(dir: String) -> Self {
-        Source { dir, files: vec![] }
+        Source {
+            dir,
+            files: vec![],
+            profile: None,
+            profile_files: vec![],
+            config: None,
+        }
     }
 
     pub fn add_file(&mut self, name: String, contents: String) {
         self.files.push(SourceFile { name, contents })
     }
 
+    pub fn add_profile_file(&mut self, name: String, contents: String) {
+        self.profile_files.push(SourceFile { name, contents })
+    }
+
     pub fn hash(&self) -> Option<u64> {
-        if self.files.is_empty() {
+        if self.files.is_empty() && self.profile_files.is_empty() {
             return None;
         }
 
@@ -77,7 +94,22 @@ impl Source {
         hasher.update(self.dir.as_bytes());
         hasher.update(FILE_SEPARATOR.as_bytes());
 
-        for file in self.files.iter() {
+        // Only hashed when there is a profile, so that hashes without one don't change.
+        if let Some(profile) = &self.profile {
+            hasher.update(GROUP_SEPARATOR.as_bytes());
+            hasher.update(profile.as_bytes());
+            hasher.update(FILE_SEPARATOR.as_bytes());
+        }
+
+        // Likewise for the config.
+        if let Some(config) = &self.config {
+            hasher.update(GROUP_SEPARATOR.as_bytes());
+            hasher.update(GROUP_SEPARATOR.as_bytes());
+            hasher.update(config.as_bytes());
+            hasher.update(FILE_SEPARATOR.as_bytes());
+        }
+
+        for file in self.files.iter().chain(self.profile_files.iter()) {
             hasher.update(file.name.as_bytes());
             hasher.update(GROUP_SEPARATOR.as_bytes());
             hasher.update(file.contents.as_bytes());
@@ -205,6 +237,9 @@ mod tests {
             Source {
                 dir: Arbitrary::arbitrary(g),
                 files: Arbitrary::arbitrary(g),
+                profile: Arbitrary::arbitrary(g),
+                profile_files: Arbitrary::arbitrary(g),
+                config: Arbitrary::arbitrary(g),
             }
         }
     }
@@ -231,6 +266,35 @@ mod tests {
         hash.hash == Hash::from_str(&hash.to_string()).unwrap().hash
     }
 
+    #[test]
+    fn profile_changes_source_hash() {
+        let mut source = Source::new("dir".to_string());
+        source.add_file("a.lisp".to_string(), "()".to_string());
+        let without = source.hash();
+
+        source.profile = Some("dev".to_string());
+        let dev = source.hash();
+        source.profile = Some("ci".to_string());
+        let ci = source.hash();
+
+        assert_ne!(without, dev);
+        assert_ne!(dev, ci);
+    }
+
+    #[test]
+    fn config_changes_source_hash() {
+        let mut source = Source::new("dir".to_string());
+        source.add_file("a.lisp".to_string(), "()".to_string());
+        let without = source.hash();
+
+        source.config = Some("clobber = true".to_string());
+        let clobber = source.hash();
+        source.config = Some("clobber = false".to_string());
+
+        assert_ne!(without, clobber);
+        assert_ne!(clobber, source.hash());
+    }
+
     #[quickcheck]
     fn source_hash_is_stable(source: Source) -> bool {
         let a = source.hash();
@@ -1,17 +1,23 @@
 use crate::{
-    cli::HookCmd,
+    cli::{FormatOptions, HookCmd},
+    config::{Config, StateMode, TrustMode},
+    features::Feature,
     get_current_dir_or_exit,
     hash::{Hash, SourceList},
-    lang::{self, ShadowLang},
-    loader, output,
+    lang::ShadowLang,
+    loader, output, secrets,
     shadowenv::Shadowenv,
+    state,
     trust::ensure_dir_tree_trusted,
     undo, unsafe_getppid,
 };
 use anyhow::{anyhow, Error};
 use serde_derive::Serialize;
 use shell_escape as shell;
-use std::{borrow::Cow, collections::HashMap, env, path::PathBuf, result::Result, str::FromStr};
+use std::{borrow::Cow, collections::HashMap, env, path::PathBuf, result::Result};
+
+/// Set by `shadowenv deactivate`: while it is, the hook leaves the shell alone.
+pub const DISABLED_KEY: &str = "__shadowenv_disabled";
 
 pub enum VariableOutputMode {
     Fish,
@@ -22,52 +28,117 @@ pub enum VariableOutputMode {
 }
 
 #[derive(Serialize, Debug)]
-struct Modifications {
+pub struct Modifications {
     schema: String,
-    exported: HashMap<String, Option<String>>,
+    pub exported: HashMap<String, Option<String>>,
     unexported: HashMap<String, Option<String>>, // Legacy. Not used, just shows up empty in json
+    /// Features provided by the shadowenv being activated.
+    features: Vec<Feature>,
 }
 
 impl Modifications {
-    fn new(exports: HashMap<String, Option<String>>) -> Modifications {
+    pub fn new(exports: HashMap<String, Option<String>>, features: Vec<Feature>) -> Modifications {
         Modifications {
             schema: "v2".to_string(),
             exported: exports,
             unexported: HashMap::new(),
+            features,
+        }
+    }
+
+    pub fn from_shadowenv(shadowenv: &Shadowenv) -> Result<Modifications, Error> {
+        let mut features: Vec<Feature> = shadowenv.features().into_iter().collect();
+        features.sort();
+        Ok(Modifications::new(shadowenv.exports()?, features))
+    }
+}
+
+impl VariableOutputMode {
+    pub fn from_options(format: &FormatOptions) -> VariableOutputMode {
+        if format.porcelain {
+            VariableOutputMode::Porcelain
+        } else if format.fish {
+            VariableOutputMode::Fish
+        } else if format.json {
+            VariableOutputMode::Json
+        } else if format.pretty_json {
+            VariableOutputMode::PrettyJson
+        } else {
+            VariableOutputMode::Posix
         }
     }
 }
 
 pub fn run(cmd: HookCmd) -> Result<(), Error> {
-    let mode = if cmd.format.porcelain {
-        VariableOutputMode::Porcelain
-    } else if cmd.format.fish {
-        VariableOutputMode::Fish
-    } else if cmd.format.json {
-        VariableOutputMode::Json
-    } else if cmd.format.pretty_json {
-        VariableOutputMode::PrettyJson
-    } else {
-        VariableOutputMode::Posix
+    if is_disabled(&env::vars().collect()) {
+        return Ok(());
+    }
+    let mode = VariableOutputMode::from_options(&cmd.format);
+    let shellpid = || {
+        cmd.shellpid
+            .unwrap_or_else(|| unsafe_getppid().expect("shadowenv bug: unable to get parent pid"))
     };
 
     let data = Shadowenv::from_env();
-    let result =
-        load_env(get_current_dir_or_exit(), data, cmd.force, cmd.clobber).and_then(|loaded_env| {
-            if let Some(shadowenv) = loaded_env {
-                apply_env(&shadowenv, mode)
+    let config = Config::load();
+    let color = config
+        .as_ref()
+        .map_or_else(|_| Config::default().color(), Config::color);
+    let cooldown = config
+        .as_ref()
+        .map_or_else(|_| Config::default().cooldown(), Config::cooldown);
+
+    // Corrupt data can't undo anything: rather than failing at every prompt, start over from the
+    // current environment, and replace it.
+    let (data, corrupt) = match undo::parse(&data) {
+        Ok(_) => (data, false),
+        Err(err) => {
+            let err = err.context("ignoring the active shadowenv, which can't be undone");
+            if let Some(warning) =
+                output::format_hook_warning(err, shellpid(), cmd.silent, color, cooldown)
+            {
+                eprintln!("{}", warning);
+            }
+            (String::new(), true)
+        }
+    };
+
+    let result = config.and_then(|config| {
+        // What __shadowenv_data must be replaced with even if no shadowenv is loaded or unloaded.
+        let (data, replacement) = if corrupt {
+            (data, Some(None))
+        } else {
+            match claim_session(&data, &config, shellpid())? {
+                Some(claimed) => (claimed.clone(), Some(Some(claimed))),
+                None => (data, None),
+            }
+        };
+
+        load_env(
+            get_current_dir_or_exit(),
+            env::vars().collect(),
+            data,
+            cmd.force,
+            cmd.clobber,
+            &config,
+        )
+        .and_then(|loaded_env| {
+            if let Some((shadowenv, config)) = loaded_env {
+                let owner = session_owner(&config, shellpid);
+                apply_env(&shadowenv, mode, &config, cmd.redact, owner)
             } else {
+                if let Some(value) = replacement {
+                    let exports = HashMap::from([("__shadowenv_data".to_string(), value)]);
+                    print_modifications(&Modifications::new(exports, vec![]), &mode);
+                }
                 Ok(())
             }
-        });
+        })
+    });
 
     // Reformat the error if needed.
     if let Err(err) = result {
-        let pid = cmd
-            .shellpid
-            .unwrap_or_else(|| unsafe_getppid().expect("shadowenv bug: unable to get parent pid"));
-
-        match output::format_hook_error(err, pid, cmd.silent) {
+        match output::format_hook_error(err, shellpid(), cmd.silent, color, cooldown) {
             Some(formatted) => Err(anyhow!(formatted)),
             None => Err(anyhow!("")),
         }
@@ -76,25 +147,83 @@ pub fn run(cmd: HookCmd) -> Result<(), Error> {
     }
 }
 
+/// A session file inherited from another process, like the shell this one was started from, goes
+/// away with it: claim it by storing its data again for `owner`, or in __shadowenv_data itself
+/// with `state = "env"`. Returns the new __shadowenv_data, if it must change.
+fn claim_session(
+    shadowenv_data: &str,
+    config: &Config,
+    owner: u32,
+) -> Result<Option<String>, Error> {
+    let (hash, data) = undo::parse(shadowenv_data)?;
+    match &data.session {
+        Some(session) if state::session_pid(session) != Some(owner as i32) => (),
+        _ => return Ok(None),
+    }
+    let claimed = match session_owner(config, || owner) {
+        Some(owner) => state::store(hash, &data, owner)?,
+        None => undo::format(hash, &data)?,
+    };
+    Ok(Some(claimed))
+}
+
+/// With `state = "file"`, keep the undo data of `shadowenv` in a session file owned by `owner`
+/// rather than in __shadowenv_data itself. Only for modifications applied to the shell: anything
+/// else, like `exec --print` or the daemon, could outlive the owner of the file.
+pub fn store_session(
+    modifications: &mut Modifications,
+    shadowenv: &Shadowenv,
+    owner: Option<u32>,
+) -> Result<(), Error> {
+    if let Some(owner) = owner {
+        let hash = Some(Hash {
+            hash: shadowenv.target_hash(),
+        });
+        let value = state::store(hash, &shadowenv.shadowenv_data(), owner)?;
+        modifications
+            .exported
+            .insert("__shadowenv_data".to_string(), Some(value));
+    }
+    Ok(())
+}
+
+/// The process owning the session file of the undo data with `state = "file"`: whoever evals our
+/// output, so usually the shell.
+pub fn session_owner(config: &Config, owner: impl FnOnce() -> u32) -> Option<u32> {
+    match config.state() {
+        StateMode::Env => None,
+        StateMode::File => Some(owner()),
+    }
+}
+
+/// Whether `shadowenv deactivate` was run in the shell `env` is from, or one it was started from:
+/// nothing loads shadowenvs there until `shadowenv activate`.
+pub fn is_disabled(env: &HashMap<String, String>) -> bool {
+    env.get(DISABLED_KEY).is_some_and(|value| !value.is_empty())
+}
+
+/// Compute the shadowenv for `pathbuf`, along with the config that applies there: `config`
+/// overridden by the config of each shadowenv being loaded.
 pub fn load_env(
     pathbuf: PathBuf,
+    env: HashMap<String, String>,
     shadowenv_data: String,
     force: bool,
     clobber: bool,
-) -> Result<Option<Shadowenv>, Error> {
-    let mut parts = shadowenv_data.splitn(2, ":");
-    let prev_hash = parts.next();
-    let json_data = parts.next().unwrap_or("{}");
-
-    let active: Option<Hash> = match prev_hash {
-        None => None,
-        Some("") => None,
-        Some("0000000000000000") => None,
-        Some(x) => Some(Hash::from_str(x)?),
-    };
+    config: &Config,
+) -> Result<Option<(Shadowenv, Config)>, Error> {
+    // "data" is used to undo changes made when activating a shadowenv
+    // we will only have "data" if already inside a shadowenv
+    let (active, data): (Option<Hash>, undo::Data) = undo::parse(&shadowenv_data)?;
 
-    // "targets" are sources of shadowenv lisp files
-    let targets = load_trusted_sources(pathbuf, false)?;
+    // "targets" are sources of shadowenv lisp files, wherever we are if the shadowenv is pinned
+    let roots = if data.pinned.is_empty() {
+        loader::find_shadowenv_paths(&pathbuf)?
+    } else {
+        data.pinned.clone()
+    };
+    let profile = loader::selected_profile(&env).map(str::to_string);
+    let targets = load_trusted_sources(&roots, profile.as_deref(), config.trust())?;
 
     let targets_hash = targets.as_ref().and_then(|targets| targets.hash());
 
@@ -113,52 +242,52 @@ pub fn load_env(
         (_, _) => (),
     }
 
-    // "data" is used to undo changes made when activating a shadowenv
-    // we will only have "data" if already inside a shadowenv
-    let data = undo::Data::from_str(json_data)?;
-    let shadowenv = Shadowenv::new(
-        env::vars().collect(),
-        data,
-        targets_hash.unwrap_or(0),
-        clobber,
-    );
+    // Only read once the roots are known to be trusted.
+    let config = config.layered(&roots)?;
+    let clobber = clobber || config.clobber();
+
+    let mut shadowenv = Shadowenv::new(env, data, targets_hash.unwrap_or(0), clobber);
+    shadowenv.set_hash_values(config.hash_values());
 
     match targets {
         Some(targets) => {
+            shadowenv.set_profile(profile);
             // run_program takes in the shadowenv, evaluates the code we found on it, and returns it
-            match ShadowLang::run_programs(shadowenv, targets) {
-                // no need to return anything descriptive here since we already
-                // had ketos print it to stderr.
-                Err(_) => Err(lang::ShadowlispError {}.into()),
-                // note the "true" since we ran code to activate/modify the shadowenv
-                Ok(shadowenv) => Ok(Some(shadowenv)),
-            }
+            let shadowenv = ShadowLang::run_programs(shadowenv, targets, &config)?;
+            Ok(Some((shadowenv, config)))
         }
         // note the "false" since we didn't have anything to run
-        None => Ok(Some(shadowenv)),
+        None => Ok(Some((shadowenv, config))),
     }
 }
 
-/// Load all Sources from the current dir, ensuring that they are all trusted.
+/// Load all Sources from the given roots, ensuring that they are all trusted unless trust checks
+/// were disabled in the config.
 fn load_trusted_sources(
-    pathbuf: PathBuf,
-    skip_trust_check: bool,
+    roots: &[PathBuf],
+    profile: Option<&str>,
+    trust: TrustMode,
 ) -> Result<Option<SourceList>, Error> {
-    #[cfg(not(test))]
-    assert!(!skip_trust_check);
-
-    let roots = loader::find_shadowenv_paths(&pathbuf)?;
     if roots.is_empty() {
         return Ok(None);
     }
 
-    if !skip_trust_check {
-        ensure_dir_tree_trusted(&roots)?;
+    if trust == TrustMode::Signed {
+        ensure_dir_tree_trusted(roots)?;
     }
 
+    load_sources(roots.to_vec(), profile)
+}
+
+/// Load the Sources for the given roots, with the programs of `profile` on top, without checking
+/// whether they are trusted.
+pub fn load_sources(
+    roots: Vec<PathBuf>,
+    profile: Option<&str>,
+) -> Result<Option<SourceList>, Error> {
     let mut source_list = SourceList::new();
     for root in roots {
-        let source = loader::load(root)?;
+        let source = loader::load(root, profile)?;
         if let Some(source) = source {
             source_list.prepend_source(source);
         }
@@ -171,53 +300,62 @@ fn load_trusted_sources(
     Ok(Some(source_list))
 }
 
-pub fn mutate_own_env(shadowenv: &Shadowenv) -> Result<(), Error> {
-    for (k, v) in shadowenv.exports()? {
-        match v {
-            Some(s) => env::set_var(k, &s),
-            None => env::remove_var(k),
-        }
+/// Print the modifications loading `shadowenv`. With `redact`, secrets are redacted in the JSON
+/// formats, the only ones not meant to be eval'd. See [store_session] for `session_owner`.
+pub fn apply_env(
+    shadowenv: &Shadowenv,
+    mode: VariableOutputMode,
+    config: &Config,
+    redact: bool,
+    session_owner: Option<u32>,
+) -> Result<(), Error> {
+    let mut modifications = Modifications::from_shadowenv(shadowenv)?;
+    store_session(&mut modifications, shadowenv, session_owner)?;
+    if redact
+        && matches!(
+            mode,
+            VariableOutputMode::Json | VariableOutputMode::PrettyJson
+        )
+    {
+        secrets::redact_modifications(&mut modifications, shadowenv, &config.secret_matcher())?;
+    }
+    print_modifications(&modifications, &mode);
+    if let VariableOutputMode::Posix | VariableOutputMode::Fish = mode {
+        output::print_activation_to_tty(shadowenv, config);
     }
-
     Ok(())
 }
 
-pub fn apply_env(shadowenv: &Shadowenv, mode: VariableOutputMode) -> Result<(), Error> {
+/// Print variable assignments in the given format, sorted by name.
+pub fn print_modifications(modifications: &Modifications, mode: &VariableOutputMode) {
+    let mut exports: Vec<(&String, &Option<String>)> = modifications.exported.iter().collect();
+    exports.sort();
+
     match mode {
         VariableOutputMode::Posix => {
-            for (k, v) in shadowenv.exports()? {
+            for (k, v) in exports {
                 match v {
-                    Some(s) => println!("export {}={}", shell_escape(&k), shell_escape(&s)),
-                    None => println!("unset {}", shell_escape(&k)),
+                    Some(s) => println!("export {}={}", shell_escape(k), shell_escape(s)),
+                    None => println!("unset {}", shell_escape(k)),
                 }
             }
-            output::print_activation_to_tty(
-                shadowenv.current_dirs(),
-                shadowenv.prev_dirs(),
-                shadowenv.features(),
-            );
         }
         VariableOutputMode::Fish => {
-            for (k, v) in shadowenv.exports()? {
+            for (k, v) in exports {
                 match v {
                     Some(s) => {
                         if k == "PATH" {
-                            let pathlist = shell_escape(&s).replace(":", "' '");
-                            println!("set -gx {} {}", shell_escape(&k), pathlist);
+                            let pathlist = shell_escape(s).replace(":", "' '");
+                            println!("set -gx {} {}", shell_escape(k), pathlist);
                         } else {
-                            println!("set -gx {} {}", shell_escape(&k), shell_escape(&s));
+                            println!("set -gx {} {}", shell_escape(k), shell_escape(s));
                         }
                     }
                     None => {
-                        println!("set -e {}", shell_escape(&k));
+                        println!("set -e {}", shell_escape(k));
                     }
                 }
             }
-            output::print_activation_to_tty(
-                shadowenv.current_dirs(),
-                shadowenv.prev_dirs(),
-                shadowenv.features(),
-            );
         }
         VariableOutputMode::Porcelain => {
             // three fields: <operation> : <name> : <value>
@@ -226,7 +364,7 @@ pub fn apply_env(shadowenv: &Shadowenv, mode: VariableOutputMode) -> Result<(),
             //          3: unset (value is empty)
             // field separator is 0x1F; record separator is 0x1E. There's a trailing record
             // separator because I'm lazy but don't depend on it not going away.
-            for (k, v) in shadowenv.exports()? {
+            for (k, v) in exports {
                 match v {
                     Some(s) => print!("\x02\x1F{}\x1F{}\x1E", k, s),
                     None => print!("\x03\x1F{}\x1F\x1E", k),
@@ -234,15 +372,12 @@ pub fn apply_env(shadowenv: &Shadowenv, mode: VariableOutputMode) -> Result<(),
             }
         }
         VariableOutputMode::Json => {
-            let modifs = Modifications::new(shadowenv.exports()?);
-            println!("{}", serde_json::to_string(&modifs).unwrap());
+            println!("{}", serde_json::to_string(modifications).unwrap());
         }
         VariableOutputMode::PrettyJson => {
-            let modifs = Modifications::new(shadowenv.exports()?);
-            println!("{}", serde_json::to_string_pretty(&modifs).unwrap());
+            println!("{}", serde_json::to_string_pretty(modifications).unwrap());
         }
     }
-    Ok(())
 }
 
 fn shell_escape(s: &str) -> String {
@@ -261,7 +396,8 @@ mod tests {
         let temp_dir = tempdir().unwrap();
         let path = temp_dir.path().join(".shadowenv.d");
         fs::create_dir(&path).unwrap();
-        let result = load_trusted_sources(path.clone(), false);
+        let roots = loader::find_shadowenv_paths(&path).unwrap();
+        let result = load_trusted_sources(&roots, None, TrustMode::Signed);
         assert!(result.is_err());
         assert_eq!(format!("directory: '{}' contains untrusted shadowenv program: `shadowenv help trust` to learn more.", path.canonicalize().unwrap().to_string_lossy()), result.err().unwrap().to_string())
     }
@@ -293,7 +429,8 @@ mod tests {
         )
         .unwrap();
 
-        let result = load_trusted_sources(base_path.join("dir1/dir2"), true)
+        let roots = loader::find_shadowenv_paths(&base_path.join("dir1/dir2")).unwrap();
+        let result = load_trusted_sources(&roots, None, TrustMode::Disabled)
             .unwrap()
             .unwrap();
 
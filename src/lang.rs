@@ -1,5 +1,8 @@
 use crate::{
+    config::Config,
+    features::Requirement,
     hash::{Source, SourceList},
+    secrets,
     shadowenv::Shadowenv,
 };
 use ketos::{Context, Error, FromValueRef, Name, Value};
@@ -15,9 +18,40 @@ use thiserror::Error;
 
 pub struct ShadowLang {}
 
+/// An error raised while evaluating a source, with the formatted ketos error and traceback.
 #[derive(Debug, Error)]
-#[error("error while evaluating shadowlisp")]
-pub struct ShadowlispError;
+#[error("error while evaluating shadowlisp:\n{details}")]
+pub struct ShadowlispError {
+    /// The hash of the source that failed, so that the error can be told apart from the next one.
+    pub source_hash: Option<u64>,
+    pub details: String,
+}
+
+#[derive(Debug, Error)]
+#[error("`{0}` is not allowed by the shadowenv config (see `allowed_builtins`)")]
+struct BuiltinNotAllowed(String);
+
+/// The builtins shadowenv adds to ketos, which `allowed_builtins` in the config applies to.
+const BUILTINS: &[&str] = &[
+    "path-concat",
+    "env/get",
+    "env/set",
+    "env/set-secret",
+    "env/no-propagate",
+    "env/append-to-pathlist",
+    "env/prepend-to-pathlist",
+    "env/remove-from-pathlist",
+    "env/remove-from-pathlist-containing",
+    "provide",
+    "require",
+    "feature/provided?",
+    "feature/version",
+    "conflicts",
+    "expand-path",
+    "profile",
+    "secret/file",
+    "secret/command",
+];
 
 macro_rules! ketos_fn2 {
     ( $scope:expr => $name:expr => fn $ident:ident
@@ -80,19 +114,46 @@ fn path_concat(vals: &mut [Value]) -> Result<String, Error> {
     Ok(res.clean().to_string_lossy().to_string())
 }
 
+/// Arguments of `require`, `conflicts` and `feature/provided?`: a feature name and an optional
+/// version requirement.
+fn requirement_args(name: Name, args: &[Value]) -> Result<Requirement, Error> {
+    let requirement = match args.len() {
+        1 => None,
+        2 => Some(<&str as FromValueRef>::from_value_ref(&args[1])?),
+        _ => {
+            return Err(From::from(ketos::exec::ExecError::ArityError {
+                name: Some(name),
+                expected: ketos::function::Arity::Range(1, 2),
+                found: args.len() as u32,
+            }));
+        }
+    };
+    let feature = <&str as FromValueRef>::from_value_ref(&args[0])?;
+
+    Requirement::new(feature, requirement).map_err(Error::custom)
+}
+
 impl ShadowLang {
-    pub fn run_programs(shadowenv: Shadowenv, sources: SourceList) -> Result<Shadowenv, Error> {
+    pub fn run_programs(
+        shadowenv: Shadowenv,
+        sources: SourceList,
+        config: &Config,
+    ) -> Result<Shadowenv, ShadowlispError> {
         let wrapper = Rc::new(ShadowenvWrapper::new(shadowenv));
         let dirs = sources.shortened_dirs();
         for source in sources.consume() {
-            Self::run(&wrapper, source)?;
+            Self::run(&wrapper, source, config)?;
         }
         let mut result = Rc::try_unwrap(wrapper).unwrap().into_inner();
         result.add_dirs(dirs);
         Ok(result)
     }
 
-    fn run(rc_wrapper: &Rc<ShadowenvWrapper>, source: Source) -> Result<(), Error> {
+    fn run(
+        rc_wrapper: &Rc<ShadowenvWrapper>,
+        source: Source,
+        config: &Config,
+    ) -> Result<(), ShadowlispError> {
         let mut restrictions = ketos::RestrictConfig::strict();
         // "Maximum size of value stack, in values"
         // This also puts a cap on the size of string literals in a single function invocation.
@@ -147,6 +208,37 @@ impl ShadowLang {
             })
         });
 
+        interp
+            .scope()
+            .add_value_with_name("env/set-secret", |name| {
+                Value::new_foreign_fn(name, move |ctx, args| {
+                    assert_args!(args, 2, name);
+
+                    let value = get_value(ctx, shadowenv_name);
+                    let shadowenv = <&ShadowenvWrapper as FromValueRef>::from_value_ref(&value)?;
+                    let name = <&str as FromValueRef>::from_value_ref(&args[0])?;
+                    let value = <&str as FromValueRef>::from_value_ref(&args[1]).ok();
+
+                    shadowenv.borrow_mut_env().set_secret(name, value);
+                    Ok(Value::Unit)
+                })
+            });
+
+        interp
+            .scope()
+            .add_value_with_name("env/no-propagate", |name| {
+                Value::new_foreign_fn(name, move |ctx, args| {
+                    assert_args!(args, 1, name);
+
+                    let value = get_value(ctx, shadowenv_name);
+                    let shadowenv = <&ShadowenvWrapper as FromValueRef>::from_value_ref(&value)?;
+                    let name = <&str as FromValueRef>::from_value_ref(&args[0])?;
+
+                    shadowenv.borrow_mut_env().add_no_propagate(name);
+                    Ok(Value::Unit)
+                })
+            });
+
         interp
             .scope()
             .add_value_with_name("env/append-to-pathlist", |name| {
@@ -231,7 +323,82 @@ impl ShadowLang {
                 };
                 let feature = <&str as FromValueRef>::from_value_ref(&args[0])?;
 
-                wrapper.borrow_mut_env().add_feature(feature, version);
+                wrapper
+                    .borrow_mut_env()
+                    .add_feature(feature, version)
+                    .map_err(Error::custom)?;
+                Ok(Value::Unit)
+            })
+        });
+
+        interp.scope().add_value_with_name("require", |name| {
+            Value::new_foreign_fn(name, move |ctx, args| {
+                let value = get_value(ctx, shadowenv_name);
+                let wrapper: &ShadowenvWrapper = FromValueRef::from_value_ref(&value)?;
+                let requirement = requirement_args(name, args)?;
+
+                wrapper
+                    .borrow_env()
+                    .require_feature(&requirement)
+                    .map_err(Error::custom)?;
+                Ok(Value::Unit)
+            })
+        });
+
+        interp
+            .scope()
+            .add_value_with_name("feature/provided?", |name| {
+                Value::new_foreign_fn(name, move |ctx, args| {
+                    let value = get_value(ctx, shadowenv_name);
+                    let wrapper: &ShadowenvWrapper = FromValueRef::from_value_ref(&value)?;
+                    let requirement = requirement_args(name, args)?;
+
+                    let provided = wrapper.borrow_env().has_feature(&requirement);
+                    Ok(Value::Bool(provided))
+                })
+            });
+
+        interp
+            .scope()
+            .add_value_with_name("feature/version", |name| {
+                Value::new_foreign_fn(name, move |ctx, args| {
+                    assert_args!(args, 1, name);
+
+                    let value = get_value(ctx, shadowenv_name);
+                    let wrapper: &ShadowenvWrapper = FromValueRef::from_value_ref(&value)?;
+                    let feature = <&str as FromValueRef>::from_value_ref(&args[0])?;
+
+                    let result = wrapper
+                        .borrow_env()
+                        .feature_version(feature)
+                        .map(|version| <String as Into<Value>>::into(version.to_string()))
+                        .unwrap_or(Value::Unit);
+                    Ok(result)
+                })
+            });
+
+        let profile = source.profile.clone();
+        interp.scope().add_value_with_name("profile", move |name| {
+            Value::new_foreign_fn(name, move |_ctx, args| {
+                assert_args!(args, 0, name);
+
+                Ok(profile
+                    .clone()
+                    .map(<String as Into<Value>>::into)
+                    .unwrap_or(Value::Unit))
+            })
+        });
+
+        interp.scope().add_value_with_name("conflicts", |name| {
+            Value::new_foreign_fn(name, move |ctx, args| {
+                let value = get_value(ctx, shadowenv_name);
+                let wrapper: &ShadowenvWrapper = FromValueRef::from_value_ref(&value)?;
+                let requirement = requirement_args(name, args)?;
+
+                wrapper
+                    .borrow_mut_env()
+                    .add_conflict(requirement)
+                    .map_err(Error::custom)?;
                 Ok(Value::Unit)
             })
         });
@@ -258,6 +425,48 @@ impl ShadowLang {
             })
         });
 
+        interp.scope().add_value_with_name("secret/file", |name| {
+            Value::new_foreign_fn(name, move |ctx, args| {
+                assert_args!(args, 1, name);
+
+                let value = get_value(ctx, shadowenv_name);
+                let wrapper: &ShadowenvWrapper = FromValueRef::from_value_ref(&value)?;
+                let path = <&str as FromValueRef>::from_value_ref(&args[0])?;
+
+                let secret = secrets::read_file(Path::new(path)).map_err(Error::custom)?;
+                wrapper.borrow_mut_env().add_secret_value(&secret);
+                Ok(<String as Into<Value>>::into(secret))
+            })
+        });
+
+        let commands = config.secrets.commands.clone().unwrap_or_default();
+        interp
+            .scope()
+            .add_value_with_name("secret/command", move |name| {
+                Value::new_foreign_fn(name, move |ctx, args| {
+                    assert_args!(args, 1, name);
+
+                    let value = get_value(ctx, shadowenv_name);
+                    let wrapper: &ShadowenvWrapper = FromValueRef::from_value_ref(&value)?;
+                    let command = <&str as FromValueRef>::from_value_ref(&args[0])?;
+
+                    let argv = commands.get(command).map(Vec::as_slice);
+                    let secret = secrets::run_command(command, argv).map_err(Error::custom)?;
+                    wrapper.borrow_mut_env().add_secret_value(&secret);
+                    Ok(<String as Into<Value>>::into(secret))
+                })
+            });
+
+        // Replace the builtins the config doesn't allow with ones that fail, so that the error
+        // says why rather than that the name is undefined.
+        for builtin in BUILTINS.iter().filter(|b| !config.builtin_allowed(b)) {
+            interp.scope().add_value_with_name(builtin, |name| {
+                Value::new_foreign_fn(name, move |_ctx, _args| {
+                    Err(Error::custom(BuiltinNotAllowed(builtin.to_string())))
+                })
+            });
+        }
+
         let prelude = r#"
           ;; Better when/if/let macros
           (macro (when pred :rest body) `(if ,pred (do ,@body) ()))
@@ -265,70 +474,81 @@ impl ShadowLang {
             `(let ,assigns (when (not (null ,(first (first assigns)))) ,@body)))
         "#;
 
-        if let Err(err) = interp.run_code(prelude, None) {
-            interp.display_error(&err);
-            if let Some(trace) = interp.get_traceback() {
-                eprintln!();
-                interp.display_trace(&trace);
+        let failed = |err: Error| {
+            let mut details = interp.format_error(&err);
+            if let Some(trace) = interp.take_traceback() {
+                details.push_str("\n\nTraceback:\n\n");
+                details.push_str(&interp.format_trace(&trace));
+            }
+            ShadowlispError {
+                source_hash: source.hash(),
+                details,
             }
-            return Err(err);
         };
 
+        interp.run_code(prelude, None).map_err(failed)?;
+
+        // The profile's programs run after the shadowenv's own.
         let mut files = source.files.clone();
         files.sort();
-        let original_path = env::current_dir();
-        let _ = env::set_current_dir(Path::new(&source.dir));
+        let mut profile_files = source.profile_files.clone();
+        profile_files.sort();
+        files.extend(profile_files);
+        let _cwd = CurrentDirGuard::enter(Path::new(&source.dir));
 
         for source_file in &files {
             let fname = format!("__shadowenv__{}", source_file.name);
             let prog = format!("(define ({} env) (do {}))", fname, source_file.contents);
 
-            // TODO: error type?
-            if let Err(err) = interp.run_code(&prog, Some(source_file.name.to_string())) {
-                interp.display_error(&err);
-                if let Some(trace) = interp.get_traceback() {
-                    eprintln!();
-                    interp.display_trace(&trace);
-                }
-                return Err(err);
-            };
+            interp
+                .run_code(&prog, Some(source_file.name.to_string()))
+                .map_err(failed)?;
         }
 
         for source_file in &files {
             let fname = format!("__shadowenv__{}", source_file.name);
-            if let Err(err) = interp.call(&fname, vec![Value::Foreign(rc_wrapper.clone())]) {
-                // TODO: error type?
-                interp.display_error(&err);
-                if let Some(trace) = interp.get_traceback() {
-                    eprintln!();
-                    interp.display_trace(&trace);
-                }
-                return Err(err);
-            };
+            interp
+                .call(&fname, vec![Value::Foreign(rc_wrapper.clone())])
+                .map_err(failed)?;
         }
-        if let Ok(dir) = original_path {
+        Ok(())
+    }
+}
+
+/// Changes the working directory until dropped, so that it's restored even if a program fails:
+/// the daemon keeps running after that.
+struct CurrentDirGuard {
+    original: Option<PathBuf>,
+}
+
+impl CurrentDirGuard {
+    fn enter(dir: &Path) -> CurrentDirGuard {
+        let original = env::current_dir().ok();
+        let _ = env::set_current_dir(dir);
+        CurrentDirGuard { original }
+    }
+}
+
+impl Drop for CurrentDirGuard {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.original {
             let _ = env::set_current_dir(dir);
         }
-        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::SecretsConfig;
     use crate::features::Feature;
-    use crate::hash::SourceFile;
     use crate::undo::Data;
-    use std::collections::{HashMap, HashSet};
+    use std::collections::{BTreeMap, HashMap, HashSet};
 
     fn build_source(content: &str) -> Source {
-        Source {
-            dir: "dir".to_string(),
-            files: vec![SourceFile {
-                name: "file.lisp".to_string(),
-                contents: content.to_string(),
-            }],
-        }
+        let mut source = Source::new("dir".to_string());
+        source.add_file("file.lisp".to_string(), content.to_string());
+        source
     }
 
     fn build_shadow_env(env_variables: Vec<(&str, &str)>) -> Shadowenv {
@@ -339,6 +559,22 @@ mod tests {
         Shadowenv::new(env, Data::new(), 0, false)
     }
 
+    #[test]
+    fn test_failing_program_restores_cwd() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut source = Source::new(temp_dir.path().to_string_lossy().to_string());
+        source.add_file("file.lisp".to_string(), "(nope)".to_string());
+
+        let cwd = env::current_dir().unwrap();
+        let result = ShadowLang::run_programs(
+            build_shadow_env(vec![]),
+            SourceList::new_with_sources(vec![source]),
+            &Config::default(),
+        );
+        assert!(result.is_err());
+        assert_eq!(env::current_dir().unwrap(), cwd);
+    }
+
     #[test]
     fn test_env_manipulation() {
         let shadowenv = build_shadow_env(vec![]);
@@ -349,8 +585,11 @@ mod tests {
             "#,
         );
 
-        let result =
-            ShadowLang::run_programs(shadowenv, SourceList::new_with_sources(vec![source]));
+        let result = ShadowLang::run_programs(
+            shadowenv,
+            SourceList::new_with_sources(vec![source]),
+            &Config::default(),
+        );
         let env = result.unwrap().exports().unwrap();
 
         assert_eq!(env["VAL_A"].as_ref().unwrap(), "42");
@@ -373,8 +612,11 @@ mod tests {
             "#,
         );
 
-        let result =
-            ShadowLang::run_programs(shadowenv, SourceList::new_with_sources(vec![source]));
+        let result = ShadowLang::run_programs(
+            shadowenv,
+            SourceList::new_with_sources(vec![source]),
+            &Config::default(),
+        );
         let env = result.unwrap().exports().unwrap();
 
         assert_eq!(env["PATH_A"].as_ref().unwrap(), "/path3:/path1:/path2");
@@ -399,13 +641,152 @@ mod tests {
             "#,
         );
 
-        let result =
-            ShadowLang::run_programs(shadowenv, SourceList::new_with_sources(vec![source]));
+        let result = ShadowLang::run_programs(
+            shadowenv,
+            SourceList::new_with_sources(vec![source]),
+            &Config::default(),
+        );
         let env = result.unwrap().exports().unwrap();
 
         assert_eq!(env["PATH"].as_ref().unwrap(), "/something_else");
     }
 
+    #[test]
+    fn test_set_secret() {
+        let source = build_source(r#"(env/set-secret "API_TOKEN" "t0ken")"#);
+
+        let shadowenv = ShadowLang::run_programs(
+            build_shadow_env(vec![]),
+            SourceList::new_with_sources(vec![source]),
+            &Config::default(),
+        )
+        .unwrap();
+
+        // Still exported as is: only its display is redacted.
+        assert_eq!(
+            shadowenv.exports().unwrap()["API_TOKEN"].as_deref(),
+            Some("t0ken")
+        );
+        assert!(shadowenv.is_secret("API_TOKEN"));
+        assert!(shadowenv.shadowenv_data().scalars[0].secret);
+    }
+
+    #[test]
+    fn test_no_propagate() {
+        let source = build_source(
+            r#"
+            (env/set "AWS_PROFILE" "dev")
+            (env/no-propagate "AWS_PROFILE")
+            "#,
+        );
+
+        let shadowenv = ShadowLang::run_programs(
+            build_shadow_env(vec![]),
+            SourceList::new_with_sources(vec![source]),
+            &Config::default(),
+        )
+        .unwrap();
+
+        // Still set in the shell: only `shadowenv exec` leaves it out.
+        assert_eq!(
+            shadowenv.exports().unwrap()["AWS_PROFILE"].as_deref(),
+            Some("dev")
+        );
+        assert!(shadowenv.no_propagate().contains("AWS_PROFILE"));
+    }
+
+    #[test]
+    fn test_secret_command() {
+        let config = Config {
+            secrets: SecretsConfig {
+                commands: Some(BTreeMap::from([
+                    (
+                        "token".to_string(),
+                        vec!["echo".to_string(), "t0ken".to_string()],
+                    ),
+                    ("empty".to_string(), vec!["true".to_string()]),
+                ])),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let source = build_source(r#"(env/set "API_TOKEN" (secret/command "token"))"#);
+
+        let shadowenv = ShadowLang::run_programs(
+            build_shadow_env(vec![]),
+            SourceList::new_with_sources(vec![source]),
+            &config,
+        )
+        .unwrap();
+
+        assert_eq!(
+            shadowenv.exports().unwrap()["API_TOKEN"].as_deref(),
+            Some("t0ken")
+        );
+        // Set to a secret, so only its hash is kept.
+        let scalar = &shadowenv.shadowenv_data().scalars[0];
+        assert!(scalar.secret);
+        assert_eq!(scalar.current, None);
+        assert_eq!(scalar.current_hash, Some(secrets::hash("t0ken")));
+
+        // Derived from a secret: just as secret. An empty secret doesn't make everything secret.
+        let source = build_source(
+            r#"
+            (env/set "API_URL" (concat "https://user:" (secret/command "token") "@example.com"))
+            (secret/command "empty")
+            (env/set "EMPTY" "")
+            "#,
+        );
+        let shadowenv = ShadowLang::run_programs(
+            build_shadow_env(vec![]),
+            SourceList::new_with_sources(vec![source]),
+            &config,
+        )
+        .unwrap();
+        assert!(shadowenv.is_secret("API_URL"));
+        assert!(!shadowenv.is_secret("EMPTY"));
+        let scalar = &shadowenv.shadowenv_data().scalars[0];
+        assert_eq!(scalar.name, "API_URL");
+        assert_eq!(scalar.current, None);
+
+        let source = build_source(r#"(secret/command "other")"#);
+        let err = ShadowLang::run_programs(
+            build_shadow_env(vec![]),
+            SourceList::new_with_sources(vec![source]),
+            &config,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("no secret command named `other`"));
+    }
+
+    #[test]
+    fn test_profile() {
+        let mut source = build_source(
+            r#"
+                (env/set "PROFILE" (if (null (profile)) "none" (profile)))
+                (env/set "DATABASE" "dev")
+            "#,
+        );
+        source.profile = Some("test".to_string());
+        source.add_profile_file(
+            "profiles/test/db.lisp".to_string(),
+            r#"(env/set "DATABASE" "test")"#.to_string(),
+        );
+
+        let env = ShadowLang::run_programs(
+            build_shadow_env(vec![]),
+            SourceList::new_with_sources(vec![source]),
+            &Config::default(),
+        )
+        .unwrap()
+        .exports()
+        .unwrap();
+
+        assert_eq!(env["PROFILE"].as_deref(), Some("test"));
+        // The profile's programs run last.
+        assert_eq!(env["DATABASE"].as_deref(), Some("test"));
+    }
+
     #[test]
     fn test_features() {
         let shadowenv = build_shadow_env(vec![]);
@@ -416,13 +797,135 @@ mod tests {
             "#,
         );
 
-        let shadowenv =
-            ShadowLang::run_programs(shadowenv, SourceList::new_with_sources(vec![source]))
-                .unwrap();
+        let shadowenv = ShadowLang::run_programs(
+            shadowenv,
+            SourceList::new_with_sources(vec![source]),
+            &Config::default(),
+        )
+        .unwrap();
         let expected = HashSet::from([Feature::new("ruby".to_string(), Some("3.1.2".to_string()))]);
         assert_eq!(shadowenv.features(), expected);
     }
 
+    fn run_features(programs: &[&str]) -> Result<Shadowenv, ShadowlispError> {
+        let sources = programs
+            .iter()
+            .enumerate()
+            .map(|(i, program)| {
+                let mut source = Source::new("dir".to_string());
+                source.add_file(format!("{}.lisp", i), program.to_string());
+                source
+            })
+            .collect();
+        ShadowLang::run_programs(
+            build_shadow_env(vec![]),
+            SourceList::new_with_sources(sources),
+            &Config::default(),
+        )
+    }
+
+    #[test]
+    fn test_require() {
+        assert!(run_features(&[r#"(provide "ruby" "3.3.0")"#, r#"(require "ruby")"#]).is_ok());
+        assert!(run_features(&[
+            r#"(provide "ruby" "3.3.0")"#,
+            r#"(require "ruby" ">= 3.2")"#
+        ])
+        .is_ok());
+
+        let err = run_features(&[
+            r#"(provide "ruby" "3.1.2")"#,
+            r#"(require "ruby" ">= 3.2")"#,
+        ])
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("`ruby` is required (version >= 3.2)"));
+
+        let err = run_features(&[r#"(require "node")"#]).unwrap_err();
+        assert!(err.to_string().contains("`node` is required"));
+
+        assert!(run_features(&[r#"(require "ruby" "newest")"#]).is_err());
+    }
+
+    #[test]
+    fn test_conflicts() {
+        // Declared after the conflicting feature was provided...
+        let err = run_features(&[r#"(provide "node" "18.2")"#, r#"(conflicts "node" "< 20")"#])
+            .unwrap_err();
+        assert!(err.to_string().contains("`node:18.2` conflicts"));
+
+        // ...or before.
+        assert!(run_features(&[r#"(conflicts "node")"#, r#"(provide "node")"#]).is_err());
+
+        assert!(
+            run_features(&[r#"(conflicts "node" "< 20")"#, r#"(provide "node" "20.1")"#]).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_feature_queries() {
+        let shadowenv = run_features(&[
+            r#"(provide "ruby" "3.3.0") (provide "ruby" "3.10.1") (provide "node")"#,
+            r#"
+                (when (feature/provided? "ruby") (env/set "HAS_RUBY" "1"))
+                (when (feature/provided? "ruby" "< 3") (env/set "OLD_RUBY" "1"))
+                (when (feature/provided? "python") (env/set "HAS_PYTHON" "1"))
+                (env/set "RUBY_VERSION" (feature/version "ruby"))
+                (env/set "NODE_VERSION" (feature/version "node"))
+            "#,
+        ])
+        .unwrap();
+
+        assert_eq!(shadowenv.get("HAS_RUBY").as_deref(), Some("1"));
+        assert_eq!(shadowenv.get("OLD_RUBY"), None);
+        assert_eq!(shadowenv.get("HAS_PYTHON"), None);
+        assert_eq!(shadowenv.get("RUBY_VERSION").as_deref(), Some("3.10.1"));
+        assert_eq!(shadowenv.get("NODE_VERSION"), None);
+    }
+
+    #[test]
+    fn test_allowed_builtins() {
+        let config = Config {
+            allowed_builtins: Some(vec!["env/set".to_string()]),
+            ..Default::default()
+        };
+
+        let source = build_source(r#"(env/set "A" "1")"#);
+        let shadowenv = ShadowLang::run_programs(
+            build_shadow_env(vec![]),
+            SourceList::new_with_sources(vec![source]),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(shadowenv.get("A").as_deref(), Some("1"));
+
+        let source = build_source(r#"(env/set "A" (expand-path "~"))"#);
+        let err = ShadowLang::run_programs(
+            build_shadow_env(vec![]),
+            SourceList::new_with_sources(vec![source]),
+            &config,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("`expand-path` is not allowed"));
+    }
+
+    #[test]
+    fn test_errors_capture_details() {
+        let source = build_source(r#"(env/set "A" (undefined-function))"#);
+        let hash = source.hash();
+
+        let err = ShadowLang::run_programs(
+            build_shadow_env(vec![]),
+            SourceList::new_with_sources(vec![source]),
+            &Config::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.source_hash, hash);
+        assert!(err.details.contains("undefined-function"));
+        assert!(err.details.contains("Traceback:"));
+    }
+
     #[test]
     fn test_expand_path() {
         let shadowenv = build_shadow_env(vec![]);
@@ -433,9 +936,12 @@ mod tests {
             "#,
         );
         let home = dirs::home_dir().map(|p| p.into_os_string().into_string().unwrap());
-        let shadowenv =
-            ShadowLang::run_programs(shadowenv, SourceList::new_with_sources(vec![source]))
-                .unwrap();
+        let shadowenv = ShadowLang::run_programs(
+            shadowenv,
+            SourceList::new_with_sources(vec![source]),
+            &Config::default(),
+        )
+        .unwrap();
         assert_eq!(shadowenv.get("EXPANDED"), home);
     }
 
@@ -459,6 +965,7 @@ mod tests {
         let shadowenv = ShadowLang::run_programs(
             shadowenv,
             SourceList::new_with_sources(vec![outer_source, inner_source]),
+            &Config::default(),
         )
         .unwrap();
         assert_eq!(shadowenv.get("TEST"), Some("TWO".to_string()));
@@ -1,12 +1,16 @@
-use crate::hash::Source;
-use anyhow::Error;
+use crate::{config::PROJECT_CONFIG_NAME, hash::Source};
+use anyhow::{anyhow, Error};
 use std::{
+    collections::HashMap,
     fs, io, iter,
     path::{Path, PathBuf},
 };
 
 pub const SHADOWENV_DIR_NAME: &str = ".shadowenv.d";
 pub const SHADOWENV_PARENT_LINK_NAME: &str = "parent";
+pub const SHADOWENV_PROFILES_DIR_NAME: &str = "profiles";
+/// Selects the profile loaded on top of each shadowenv's own programs.
+pub const PROFILE_KEY: &str = "SHADOWENV_PROFILE";
 
 #[derive(thiserror::Error, Debug)]
 pub enum TraversalError {
@@ -72,7 +76,7 @@ pub fn find_shadowenv_paths(at: &Path) -> Result<Vec<PathBuf>, TraversalError> {
 
 /// Attempts to find the closest shadowenv folder to `at`.
 /// Returns a canonicalized path to the found shadowenv folder.
-fn closest_shadowenv(at: &Path) -> Result<Option<PathBuf>, TraversalError> {
+pub fn closest_shadowenv(at: &Path) -> Result<Option<PathBuf>, TraversalError> {
     for ancestor in at.ancestors() {
         let dirpath = ancestor.join(SHADOWENV_DIR_NAME);
         let metadata = match fs::metadata(&dirpath) {
@@ -149,13 +153,44 @@ fn resolve_shadowenv_parents(from_shadowenv: &PathBuf) -> Result<Vec<PathBuf>, T
     Ok(iter::once(resolved_parent).chain(parents).collect())
 }
 
-/// Load all .lisp files in the directory pointed by `dirpath` storing their names and contents as
-/// `SourceFiles` inside a `Source` struct.
+/// Load all .lisp files in the directory pointed by `dirpath`, followed by those of `profile` in
+/// `profiles/<profile>` if it has that profile, storing their names and contents as `SourceFiles`
+/// inside a `Source` struct.
 ///
 /// Note that this function assumes that the dirpath is trusted.
-pub fn load(dirpath: PathBuf) -> Result<Option<Source>, Error> {
+pub fn load(dirpath: PathBuf, profile: Option<&str>) -> Result<Option<Source>, Error> {
     let mut source = Source::new(dirpath.parent().unwrap().to_string_lossy().to_string());
 
+    for (name, contents) in read_programs(&dirpath)? {
+        source.add_file(name, contents);
+    }
+
+    if let Some(profile) = profile {
+        validate_profile_name(profile)?;
+        let profile_dir = dirpath.join(SHADOWENV_PROFILES_DIR_NAME).join(profile);
+        if profile_dir.is_dir() {
+            for (name, contents) in read_programs(&profile_dir)? {
+                // Keep them apart from the shadowenv's own programs of the same name.
+                let name = format!("{}/{}/{}", SHADOWENV_PROFILES_DIR_NAME, profile, name);
+                source.add_profile_file(name, contents);
+            }
+        }
+        source.profile = Some(profile.to_string());
+    }
+
+    if source.files.is_empty() && source.profile_files.is_empty() {
+        return Ok(None);
+    }
+
+    let config_path = dirpath.join(PROJECT_CONFIG_NAME);
+    if config_path.is_file() {
+        source.config = Some(fs::read_to_string(config_path)?);
+    }
+    Ok(Some(source))
+}
+
+fn read_programs(dirpath: &Path) -> Result<Vec<(String, String)>, Error> {
+    let mut programs = vec![];
     for entry in fs::read_dir(dirpath)?.flatten() {
         let path = entry.path();
         if path.is_file() {
@@ -165,14 +200,47 @@ pub fn load(dirpath: PathBuf) -> Result<Option<Source>, Error> {
                 continue;
             }
             let contents = fs::read_to_string(&path)?;
-            source.add_file(basename, contents);
+            programs.push((basename, contents));
         }
     }
+    Ok(programs)
+}
 
-    if source.files.is_empty() {
-        return Ok(None);
+/// The profile selected in `env`, if any.
+pub fn selected_profile(env: &HashMap<String, String>) -> Option<&str> {
+    env.get(PROFILE_KEY)
+        .map(String::as_str)
+        .filter(|profile| !profile.is_empty())
+}
+
+/// Profiles are directory names: reject anything that would reach elsewhere.
+pub fn validate_profile_name(profile: &str) -> Result<(), Error> {
+    let valid = !profile.starts_with('.')
+        && profile
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow!("invalid profile name: `{}`", profile))
     }
-    Ok(Some(source))
+}
+
+/// The names of the profiles the shadowenv at `dirpath` defines, sorted.
+pub fn profiles(dirpath: &Path) -> Result<Vec<String>, Error> {
+    let profiles_dir = dirpath.join(SHADOWENV_PROFILES_DIR_NAME);
+    if !profiles_dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut profiles: Vec<String> = fs::read_dir(profiles_dir)?
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| validate_profile_name(name).is_ok())
+        .collect();
+    profiles.sort();
+    Ok(profiles)
 }
 
 #[cfg(test)]
@@ -187,7 +255,7 @@ mod tests {
         let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", "simple"]
             .iter()
             .collect();
-        let res = load(path);
+        let res = load(path, None);
         let source = res.unwrap().unwrap();
         assert_eq!(source.files.len(), 2, "it should contain 2 files");
         let mut files = source.files.clone();
@@ -211,6 +279,29 @@ mod tests {
         assert_eq!(files, expected)
     }
 
+    #[test]
+    fn test_load_profile() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join(".shadowenv.d");
+        let dev = root.join("profiles/dev");
+        create_all(&[&dev, &root.join("profiles/ci")]);
+        fs::write(root.join("base.lisp"), "(env/set \"A\" \"1\")").unwrap();
+        fs::write(dev.join("base.lisp"), "(env/set \"A\" \"2\")").unwrap();
+
+        let source = load(root.clone(), Some("dev")).unwrap().unwrap();
+        assert_eq!(source.files.len(), 1);
+        assert_eq!(source.profile, Some("dev".to_string()));
+        assert_eq!(source.profile_files[0].name, "profiles/dev/base.lisp");
+
+        // A profile this shadowenv doesn't define still counts, since programs can check it.
+        let source = load(root.clone(), Some("test")).unwrap().unwrap();
+        assert!(source.profile_files.is_empty());
+        assert_eq!(source.profile, Some("test".to_string()));
+
+        assert!(load(root.clone(), Some("../dev")).is_err());
+        assert_eq!(profiles(&root).unwrap(), vec!["ci", "dev"]);
+    }
+
     #[test]
     fn closest_shadowenv_from_subfolder() {
         let temp_dir = tempdir().unwrap();
@@ -254,14 +345,14 @@ mod tests {
         // Three -> two
         symlink(
             &shadowenv_two_path,
-            &shadowenv_three_path.join(SHADOWENV_PARENT_LINK_NAME),
+            shadowenv_three_path.join(SHADOWENV_PARENT_LINK_NAME),
         )
         .unwrap();
 
         // Two -> one
         symlink(
             &shadowenv_one_path,
-            &shadowenv_two_path.join(SHADOWENV_PARENT_LINK_NAME),
+            shadowenv_two_path.join(SHADOWENV_PARENT_LINK_NAME),
         )
         .unwrap();
 
@@ -289,7 +380,7 @@ mod tests {
         // Two -> one sibling
         symlink(
             &shadowenv_one_sibling_path,
-            &shadowenv_two_path.join(SHADOWENV_PARENT_LINK_NAME),
+            shadowenv_two_path.join(SHADOWENV_PARENT_LINK_NAME),
         )
         .unwrap();
 
@@ -312,14 +403,14 @@ mod tests {
         // Two -> one
         symlink(
             &shadowenv_one_path,
-            &shadowenv_two_path.join(SHADOWENV_PARENT_LINK_NAME),
+            shadowenv_two_path.join(SHADOWENV_PARENT_LINK_NAME),
         )
         .unwrap();
 
         // One -> two
         symlink(
             &shadowenv_two_path,
-            &shadowenv_one_path.join(SHADOWENV_PARENT_LINK_NAME),
+            shadowenv_one_path.join(SHADOWENV_PARENT_LINK_NAME),
         )
         .unwrap();
 
@@ -339,8 +430,8 @@ mod tests {
 
         // Shadowenv -> not a shadowenv
         symlink(
-            &base_path.join("top"),
-            &shadowenv.join(SHADOWENV_PARENT_LINK_NAME),
+            base_path.join("top"),
+            shadowenv.join(SHADOWENV_PARENT_LINK_NAME),
         )
         .unwrap();
 
@@ -366,8 +457,8 @@ mod tests {
                     parent_link_path,
                     error,
                 } =>
-                    parent_link_path == shadowenv_invalid_parent_file.to_string_lossy().to_string()
-                        && error == "Not a symlink".to_owned(),
+                    parent_link_path == shadowenv_invalid_parent_file.to_string_lossy()
+                        && error == "Not a symlink",
                 _ => false,
             }
         );
@@ -399,7 +490,7 @@ mod tests {
                     parent_link_path,
                     error,
                 } =>
-                    parent_link_path == link_path.to_string_lossy().to_string()
+                    parent_link_path == link_path.to_string_lossy()
                         && error.contains("No such file or directory"),
                 _ => false,
             }
@@ -1,4 +1,7 @@
+mod activation;
 mod cli;
+mod config;
+mod daemon;
 mod diff;
 mod exec_cmd;
 mod features;
@@ -8,10 +11,15 @@ mod init;
 mod lang;
 mod loader;
 mod output;
+mod profile;
 mod prompt_widget;
+mod secrets;
 mod shadowenv;
+mod state;
 mod trust;
 mod undo;
+mod watch;
+mod xdg;
 
 use anyhow::{anyhow, Error};
 use clap::Parser;
@@ -21,18 +29,25 @@ fn main() {
     use cli::ShadowenvApp::*;
 
     let result = match cli::ShadowenvApp::parse() {
-        Diff(cmd) => {
-            diff::run(cmd);
-            Ok(())
-        }
+        Activate(cmd) => activation::activate(cmd),
+        Config(cmd) => config::run(cmd),
+        Daemon(cmd) => daemon::run(cmd),
+        Deactivate(cmd) => activation::deactivate(cmd),
+        Diff(cmd) => diff::run(cmd),
         Exec(cmd) => exec_cmd::run(cmd),
+        Features(cmd) => features::run(cmd),
         Hook(cmd) => hook::run(cmd),
         Init(cmd) => init::run(cmd),
+        Pin(cmd) => activation::pin(cmd),
+        Profile(cmd) => profile::run(cmd),
+        Repair(cmd) => activation::repair(cmd),
         Trust(_) => trust::run(),
-        PromptWidget(_) => {
-            prompt_widget::run();
+        Unpin(cmd) => activation::unpin(cmd),
+        PromptWidget(cmd) => {
+            prompt_widget::run(cmd);
             Ok(())
         }
+        Watch(cmd) => watch::run(cmd),
     };
 
     if let Err(err) = result {
@@ -1,10 +1,19 @@
-use crate::{features::Feature, loader, trust};
+use crate::{
+    config::{Config, Verbosity},
+    lang::ShadowlispError,
+    loader::{self, TraversalError},
+    shadowenv::Shadowenv,
+    trust,
+    undo::CorruptData,
+    xdg,
+};
 use anyhow::{anyhow, Error};
 use regex::Regex;
 use std::{
-    collections::HashSet,
+    collections::{hash_map::DefaultHasher, HashSet},
     env,
     fs::{self, OpenOptions},
+    hash::{Hash, Hasher},
     io::IsTerminal,
     path::{Path, PathBuf},
     time::{Duration, SystemTime},
@@ -18,54 +27,197 @@ const SHADOWENV: &str = concat!(
     "\x1b[38;5;244mw\x1b[38;5;243me\x1b[38;5;242mn\x1b[38;5;241mv\x1b[38;5;240m",
 );
 
-const COOLDOWN_SECONDS: u64 = 5;
+pub fn format_hook_error(
+    err: Error,
+    shellpid: u32,
+    silent: bool,
+    color: bool,
+    cooldown: Duration,
+) -> Option<String> {
+    if suppressed(&err, shellpid, silent, cooldown) {
+        return None;
+    }
 
-fn cooldown() -> Duration {
-    Duration::from_secs(COOLDOWN_SECONDS)
+    // Print the ketos error and traceback as they are, before the summary.
+    let (details, err) = match err.downcast::<ShadowlispError>() {
+        Ok(err) => (
+            format!("{}\n", err.details),
+            backticks_to_bright_green(anyhow!("error while evaluating shadowlisp"), RED),
+        ),
+        Err(err) => (String::new(), backticks_to_bright_green(err, RED)),
+    };
+    let formatted = format!("{}{} {}failure: {}\x1b[0m", details, SHADOWENV, RED, err);
+    Some(if color {
+        formatted
+    } else {
+        strip_colors(&formatted)
+    })
 }
 
-pub fn format_hook_error(err: Error, shellpid: u32, silent: bool) -> Option<String> {
-    if silent || matches!(check_and_trigger_cooldown(&err, shellpid), Ok(true)) {
+/// Like [format_hook_error], for errors the hook recovered from. The whole chain of `err` is shown.
+pub fn format_hook_warning(
+    err: Error,
+    shellpid: u32,
+    silent: bool,
+    color: bool,
+    cooldown: Duration,
+) -> Option<String> {
+    if suppressed(&err, shellpid, silent, cooldown) {
         return None;
     }
 
-    let err = backticks_to_bright_green(err);
-    Some(format!("{} \x1b[1;31mfailure: {}\x1b[0m", SHADOWENV, err))
+    let err = backticks_to_bright_green(anyhow!("{:#}", err), YELLOW);
+    let formatted = format!("{} {}warning: {}\x1b[0m", SHADOWENV, YELLOW, err);
+    Some(if color {
+        formatted
+    } else {
+        strip_colors(&formatted)
+    })
 }
 
-pub fn print_activation_to_tty(
-    current_dirs: HashSet<PathBuf>,
-    prev_dirs: HashSet<PathBuf>,
-    features: HashSet<Feature>,
-) {
-    if !should_print_activation() {
+fn suppressed(err: &Error, shellpid: u32, silent: bool, cooldown: Duration) -> bool {
+    silent
+        || matches!(
+            check_and_trigger_cooldown(err, shellpid, cooldown),
+            Ok(true)
+        )
+}
+
+pub fn print_activation_to_tty(shadowenv: &Shadowenv, config: &Config) {
+    if !should_print_activation(config) {
         return;
     }
+    eprintln!("{}", format_activation(shadowenv, config));
+}
+
+fn format_activation(shadowenv: &Shadowenv, config: &Config) -> String {
+    let current_dirs = shadowenv.current_dirs();
+    let prev_dirs = shadowenv.prev_dirs();
     let added_dirs: HashSet<PathBuf> = current_dirs.difference(&prev_dirs).cloned().collect();
     let removed_dirs: HashSet<PathBuf> = prev_dirs.difference(&current_dirs).cloned().collect();
 
-    let feature_list = if !features.is_empty() {
-        format!(
-            " \x1b[1;38;5;245m{}",
-            features
-                .iter()
-                .map(|s| s.to_string())
-                .collect::<Vec<_>>()
-                .join("\x1b[38;5;240m,\x1b[1;38;5;245m")
-        )
-    } else {
-        String::new()
+    // Only mention features that weren't already provided before, e.g. when switching versions.
+    let prev_features = shadowenv.prev_features();
+    let mut all_features = shadowenv
+        .features()
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+    all_features.sort();
+    let mut features = shadowenv
+        .features()
+        .difference(&prev_features)
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+    features.sort();
+
+    let banner = match &config.banner.template {
+        Some(template) => render_template(
+            template,
+            &[
+                ("shadowenv", SHADOWENV.to_string() + "\x1b[0m"),
+                (
+                    "dirs",
+                    dir_diff(&added_dirs, &removed_dirs).unwrap_or_default(),
+                ),
+                ("roots", join_dirs(&current_dirs)),
+                ("added", join_dirs(&added_dirs)),
+                ("removed", join_dirs(&removed_dirs)),
+                ("features", features.join(",")),
+                ("all_features", all_features.join(",")),
+                ("hash", format!("{:016x}", shadowenv.target_hash())),
+                (
+                    "pinned",
+                    if shadowenv.pinned() { "pinned" } else { "" }.to_string(),
+                ),
+                (
+                    "profile",
+                    shadowenv.profile().unwrap_or_default().to_string(),
+                ),
+            ],
+        ),
+        None => {
+            let feature_list = if !features.is_empty() && config.verbosity() == Verbosity::Full {
+                format!(
+                    " \x1b[1;38;5;245m{}",
+                    features.join("\x1b[38;5;240m,\x1b[1;38;5;245m")
+                )
+            } else {
+                String::new()
+            };
+
+            let profile = match shadowenv.profile() {
+                Some(profile) => format!(" \x1b[0;38;5;245m({})", profile),
+                None => String::new(),
+            };
+            let pinned = if shadowenv.pinned() {
+                " \x1b[0;38;5;240m(pinned)"
+            } else {
+                ""
+            };
+
+            format!(
+                "\x1b[1;34m{}{}{}{}{}\x1b[0m",
+                SHADOWENV,
+                dir_diff(&added_dirs, &removed_dirs).unwrap_or_default(),
+                profile,
+                feature_list,
+                pinned
+            )
+        }
     };
 
-    eprintln!(
-        "\x1b[1;34m{}{}{}\x1b[0m",
-        SHADOWENV,
-        dir_diff(added_dirs, removed_dirs).unwrap_or_default(),
-        feature_list
-    );
+    if config.color() {
+        banner
+    } else {
+        strip_colors(&banner)
+    }
+}
+
+/// Replace each `{name}` in `template` with its value. Unknown placeholders are left as they are.
+pub fn render_template(template: &str, values: &[(&str, String)]) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let value = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                output.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                output.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Remove ANSI color escape sequences, for when colors are disabled.
+pub fn strip_colors(s: &str) -> String {
+    let re = Regex::new("\x1b\\[[0-9;]*[A-Za-z]").unwrap();
+    re.replace_all(s, "").to_string()
+}
+
+fn join_dirs(dirs: &HashSet<PathBuf>) -> String {
+    let mut dirs: Vec<String> = dirs.iter().map(|d| d.display().to_string()).collect();
+    dirs.sort();
+    dirs.join(", ")
 }
 
-fn dir_diff(added_dirs: HashSet<PathBuf>, removed_dirs: HashSet<PathBuf>) -> Option<String> {
+fn dir_diff(added_dirs: &HashSet<PathBuf>, removed_dirs: &HashSet<PathBuf>) -> Option<String> {
     if added_dirs.is_empty() && removed_dirs.is_empty() {
         return None;
     }
@@ -91,31 +243,46 @@ fn dir_diff(added_dirs: HashSet<PathBuf>, removed_dirs: HashSet<PathBuf>) -> Opt
     Some(output)
 }
 
-fn backticks_to_bright_green(err: Error) -> String {
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+
+/// `color` is the color of the rest of the message, to go back to.
+fn backticks_to_bright_green(err: Error, color: &str) -> String {
     let re = Regex::new(r"`(.*?)`").unwrap();
     // this is almost certainly not the best way to do this, but this runs at most once per
     // execution so I only care so much.
     let before = format!("{}", err);
-    re.replace_all(before.as_ref(), "\x1b[1;32m$1\x1b[1;31m")
+    re.replace_all(before.as_ref(), format!("\x1b[1;32m$1{}", color))
         .to_string()
 }
 
-fn check_and_trigger_cooldown(err: &Error, shellpid: u32) -> Result<bool, Error> {
-    // if no .shadowenv.d, then Err(_) just means no cooldown: always display error.
-    let roots = loader::find_shadowenv_paths(&env::current_dir()?)?;
-    if roots.is_empty() {
-        return Err(anyhow!("no .shadowenv.d"));
-    }
+const COOLDOWN_DIR_NAME: &str = "cooldown";
+const STALE_ERROR_TIMEOUT: Duration = Duration::from_secs(300);
 
-    let root = roots.first().unwrap();
+fn check_and_trigger_cooldown(
+    err: &Error,
+    shellpid: u32,
+    cooldown: Duration,
+) -> Result<bool, Error> {
+    // if no .shadowenv.d, then Err(_) just means no cooldown: always display error.
+    // Only look for the closest one: following parent links is what fails on traversal errors.
+    // Corrupt data is the same wherever the shell is, though.
+    let root = if err.is::<CorruptData>() {
+        PathBuf::from("/")
+    } else {
+        loader::closest_shadowenv(&env::current_dir()?)?
+            .ok_or_else(|| anyhow!("no .shadowenv.d"))?
+    };
 
-    let _ = clean_up_stale_errors(root, Duration::new(300, 0));
+    let (errindex, cause) =
+        cooldown_key(err).ok_or_else(|| anyhow!("error not subject to cooldown"))?;
 
-    let errindex = cooldown_index(err).ok_or_else(|| anyhow!("error not subject to cooldown"))?;
+    let dir = cooldown_dir()?;
+    let _ = clean_up_stale_errors(&dir, STALE_ERROR_TIMEOUT.max(cooldown));
 
-    let errfilepath = err_file(root, errindex, shellpid)?;
+    let errfilepath = err_file(&dir, &root, errindex, cause, shellpid);
 
-    match check_cooldown_sentinel(&errfilepath, cooldown()) {
+    match check_cooldown_sentinel(&errfilepath, cooldown) {
         Ok(true) => Ok(true),
         _ => {
             create_cooldown_sentinel(errfilepath)?;
@@ -124,32 +291,74 @@ fn check_and_trigger_cooldown(err: &Error, shellpid: u32) -> Result<bool, Error>
     }
 }
 
-fn cooldown_index(err: &Error) -> Option<u32> {
-    err.downcast_ref::<trust::NotTrusted>().map(|_| 0)
+/// The class of an error and a hash of what caused it, e.g. the source that failed to evaluate: an
+/// error stays quiet during the cooldown, unless its cause changes.
+fn cooldown_key(err: &Error) -> Option<(u32, u64)> {
+    if let Some(err) = err.downcast_ref::<trust::NotTrusted>() {
+        Some((0, hash_of(&err.untrusted_directories)))
+    } else if let Some(err) = err.downcast_ref::<ShadowlispError>() {
+        Some((1, err.source_hash.unwrap_or_else(|| hash_of(&err.details))))
+    } else if let Some(err) = err.downcast_ref::<TraversalError>() {
+        Some((2, hash_of(&err.to_string())))
+    } else {
+        err.downcast_ref::<CorruptData>()
+            .map(|err| (3, hash_of(&err.reason)))
+    }
+}
+
+fn hash_of<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Sentinels are kept out of the project, which may well be read-only.
+fn cooldown_dir() -> Result<PathBuf, Error> {
+    let dir = xdg::session_dir()?.join(COOLDOWN_DIR_NAME);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
 }
 
-fn clean_up_stale_errors(root: &PathBuf, timeout: Duration) -> Result<(), Error> {
+/// Remove the sentinels of shells that exited, and those too old to matter anymore.
+fn clean_up_stale_errors(dir: &Path, timeout: Duration) -> Result<(), Error> {
     let now = SystemTime::now();
-    if root.is_dir() {
-        for entry in fs::read_dir(root)? {
-            let entry = entry?;
-            if !entry.file_name().to_string_lossy().starts_with(".error-") {
-                continue;
-            }
-            if let Ok(mtime) = entry.metadata().and_then(|md| md.modified()) {
-                if let Ok(duration) = now.duration_since(mtime) {
-                    if duration > timeout {
-                        let _ = fs::remove_file(entry.path());
-                    }
-                }
-            }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        let shell_exited = match name.split('-').nth(1).and_then(|pid| pid.parse().ok()) {
+            Some(pid) => !process_alive(pid),
+            None => continue,
+        };
+        let expired = entry
+            .metadata()
+            .and_then(|md| md.modified())
+            .ok()
+            .and_then(|mtime| now.duration_since(mtime).ok())
+            .is_some_and(|age| age > timeout);
+
+        if shell_exited || expired {
+            let _ = fs::remove_file(entry.path());
         }
     }
     Ok(())
 }
 
-fn err_file(root: &Path, errindex: u32, shellpid: u32) -> Result<PathBuf, Error> {
-    Ok(root.join(format!(".error-{}-{}", errindex, shellpid)))
+pub fn process_alive(pid: i32) -> bool {
+    // Signal 0 only checks whether the process exists. EPERM means it does, but isn't ours.
+    let signalled = unsafe { libc::kill(pid, 0) == 0 };
+    signalled || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// `<hash of the root>-<shell pid>-<error class>-<hash of the cause>`
+fn err_file(dir: &Path, root: &Path, errindex: u32, cause: u64, shellpid: u32) -> PathBuf {
+    dir.join(format!(
+        "{:016x}-{}-{}-{:016x}",
+        hash_of(&root),
+        shellpid,
+        errindex,
+        cause
+    ))
 }
 
 // return value of Ok(true) indicates it's on cooldown and should be suppressed.
@@ -172,15 +381,145 @@ fn create_cooldown_sentinel(path: PathBuf) -> Result<(), Error> {
     Ok(())
 }
 
-fn should_print_activation() -> bool {
-    let configured_to_print: bool;
-    match env::var("SHADOWENV_SILENT") {
-        Ok(value) => match value.to_lowercase().as_str() {
-            "0" | "false" | "no" | "" => configured_to_print = true,
-            _ => configured_to_print = false,
-        },
-        Err(_) => configured_to_print = true,
+fn should_print_activation(config: &Config) -> bool {
+    let silenced = match env::var("SHADOWENV_SILENT") {
+        Ok(value) => !matches!(value.to_lowercase().as_str(), "0" | "false" | "no" | ""),
+        Err(_) => false,
     };
 
-    std::io::stderr().is_terminal() && configured_to_print
+    std::io::stderr().is_terminal() && !silenced && config.verbosity() != Verbosity::Silent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{features::Feature, undo::Data};
+    use std::collections::HashMap;
+
+    fn build_shadowenv() -> Shadowenv {
+        let mut shadowenv = Shadowenv::new(HashMap::new(), Data::new(), 0x2a, false);
+        shadowenv.add_dirs(vec![PathBuf::from("proj"), PathBuf::from("proj/sub")]);
+        shadowenv.add_feature("ruby", Some("3.3")).unwrap();
+        shadowenv.add_feature("node", None).unwrap();
+        shadowenv
+    }
+
+    #[test]
+    fn test_render_template() {
+        let values = [("a", "1".to_string()), ("b", "2".to_string())];
+        assert_eq!(render_template("{a}-{b}", &values), "1-2");
+        assert_eq!(render_template("{a} {c} {", &values), "1 {c} {");
+        assert_eq!(render_template("{{a}}", &values), "{1}");
+    }
+
+    #[test]
+    fn test_cooldown_key() {
+        let lisp_error = |hash| -> Error {
+            ShadowlispError {
+                source_hash: Some(hash),
+                details: "error: oops".to_string(),
+            }
+            .into()
+        };
+        assert_eq!(cooldown_key(&lisp_error(1)), cooldown_key(&lisp_error(1)));
+        assert_ne!(cooldown_key(&lisp_error(1)), cooldown_key(&lisp_error(2)));
+
+        let traversal_error: Error = TraversalError::SelfReferential {
+            shadowenv_path: "/a/.shadowenv.d".to_string(),
+        }
+        .into();
+        assert_eq!(cooldown_key(&traversal_error).unwrap().0, 2);
+
+        assert_eq!(cooldown_key(&anyhow!("something else")), None);
+    }
+
+    #[test]
+    fn test_clean_up_stale_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Path::new("/proj/.shadowenv.d");
+        let alive = err_file(dir.path(), root, 0, 1, std::process::id());
+        // pid_max is at most 2^22 on Linux.
+        let exited = err_file(dir.path(), root, 0, 1, i32::MAX as u32);
+        create_cooldown_sentinel(alive.clone()).unwrap();
+        create_cooldown_sentinel(exited.clone()).unwrap();
+
+        clean_up_stale_errors(dir.path(), STALE_ERROR_TIMEOUT).unwrap();
+        assert!(alive.exists());
+        assert!(!exited.exists());
+
+        clean_up_stale_errors(dir.path(), Duration::ZERO).unwrap();
+        assert!(!alive.exists());
+    }
+
+    #[test]
+    fn test_strip_colors() {
+        assert_eq!(
+            strip_colors("\x1b[1;34mblue\x1b[0m \x1b[38;5;240mgray"),
+            "blue gray"
+        );
+    }
+
+    #[test]
+    fn test_default_banner_without_color() {
+        let config = Config {
+            color: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_activation(&build_shadowenv(), &config),
+            "░shadowenv[++] node,ruby:3.3"
+        );
+    }
+
+    #[test]
+    fn test_minimal_banner() {
+        let mut config = Config {
+            color: Some(false),
+            ..Default::default()
+        };
+        config.banner.verbosity = Some(Verbosity::Minimal);
+        assert_eq!(
+            format_activation(&build_shadowenv(), &config),
+            "░shadowenv[++]"
+        );
+    }
+
+    #[test]
+    fn test_banner_template() {
+        let mut config = Config {
+            color: Some(false),
+            ..Default::default()
+        };
+        config.banner.template = Some("{roots} | +{added} -{removed} | {features} | {hash}".into());
+        assert_eq!(
+            format_activation(&build_shadowenv(), &config),
+            "proj, proj/sub | +proj, proj/sub - | node,ruby:3.3 | 000000000000002a"
+        );
+    }
+
+    #[test]
+    fn test_banner_only_shows_changed_features() {
+        let data = Data {
+            features: vec![
+                Feature::new("node".to_string(), None),
+                Feature::new("ruby".to_string(), Some("3.2".to_string())),
+            ],
+            ..Default::default()
+        };
+        let mut shadowenv = Shadowenv::new(HashMap::new(), data, 0x2a, false);
+        shadowenv.add_feature("ruby", Some("3.3")).unwrap();
+        shadowenv.add_feature("node", None).unwrap();
+
+        let mut config = Config {
+            color: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_activation(&shadowenv, &config),
+            "░shadowenv ruby:3.3"
+        );
+
+        config.banner.template = Some("{all_features}".into());
+        assert_eq!(format_activation(&shadowenv, &config), "node,ruby:3.3");
+    }
 }
@@ -0,0 +1,72 @@
+use crate::{
+    activation,
+    cli::ProfileCmd,
+    get_current_dir_or_exit,
+    loader::{self, PROFILE_KEY},
+    shadowenv::Shadowenv,
+    undo,
+};
+use anyhow::{anyhow, Error};
+use std::{
+    collections::{BTreeSet, HashMap},
+    env,
+    path::PathBuf,
+};
+
+pub fn run(cmd: ProfileCmd) -> Result<(), Error> {
+    match cmd {
+        ProfileCmd::Use(cmd) => {
+            loader::validate_profile_name(&cmd.name)?;
+            let available = available_profiles()?;
+            if available
+                .as_ref()
+                .is_some_and(|profiles| !profiles.contains(&cmd.name))
+            {
+                return Err(anyhow!("no profile named `{}` in this shadowenv", cmd.name));
+            }
+
+            let mut vars: HashMap<String, String> = env::vars().collect();
+            vars.insert(PROFILE_KEY.to_string(), cmd.name.clone());
+            let extra = HashMap::from([(PROFILE_KEY.to_string(), Some(cmd.name))]);
+            activation::reload(vars, Shadowenv::from_env(), extra, cmd.clobber, &cmd.format)
+        }
+        ProfileCmd::Clear(cmd) => {
+            let mut vars: HashMap<String, String> = env::vars().collect();
+            vars.remove(PROFILE_KEY);
+            let extra = HashMap::from([(PROFILE_KEY.to_string(), None)]);
+            activation::reload(vars, Shadowenv::from_env(), extra, cmd.clobber, &cmd.format)
+        }
+        ProfileCmd::List => {
+            let selected = env::var(PROFILE_KEY).ok();
+            for profile in available_profiles()?.unwrap_or_default() {
+                let marker = if selected.as_ref() == Some(&profile) {
+                    "*"
+                } else {
+                    " "
+                };
+                println!("{} {}", marker, profile);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The profiles defined by any of the shadowenvs that would be loaded here: the pinned ones if
+/// there are any. None if there is no shadowenv at all.
+fn available_profiles() -> Result<Option<BTreeSet<String>>, Error> {
+    let (_, data) = undo::parse(&Shadowenv::from_env())?;
+    let roots: Vec<PathBuf> = if data.pinned.is_empty() {
+        loader::find_shadowenv_paths(&get_current_dir_or_exit())?
+    } else {
+        data.pinned
+    };
+    if roots.is_empty() {
+        return Ok(None);
+    }
+
+    let mut profiles = BTreeSet::new();
+    for root in roots {
+        profiles.extend(loader::profiles(&root)?);
+    }
+    Ok(Some(profiles))
+}
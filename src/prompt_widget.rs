@@ -1,8 +1,180 @@
-use crate::shadowenv::Shadowenv;
+use crate::{
+    cli::PromptWidgetCmd,
+    config::Config,
+    features::Feature,
+    output::{render_template, strip_colors},
+    shadowenv::Shadowenv,
+    undo,
+};
+use serde_derive::Serialize;
+use std::path::PathBuf;
 
-pub fn run() {
-    let data = Shadowenv::from_env();
-    if !data.is_empty() && !data.starts_with("00000000") {
-        print!("\x1b[38;5;245m░\x1b[0m");
+const DEFAULT_WIDGET: &str = "\x1b[38;5;245m░\x1b[0m";
+const DEFAULT_PINNED_WIDGET: &str = "\x1b[38;5;245m▒\x1b[0m";
+
+/// The active shadowenv, as far as it can be told from `__shadowenv_data` alone.
+#[derive(Serialize, Debug, Default, PartialEq)]
+struct Status {
+    active: bool,
+    /// The name of the directory of the innermost shadowenv.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+    roots: Vec<PathBuf>,
+    features: Vec<Feature>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    /// Whether the shadowenv is pinned with `shadowenv pin`.
+    pinned: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+}
+
+impl Status {
+    fn from_data(data: &str) -> Status {
+        let (hash, data) = match undo::parse(data) {
+            Ok((Some(hash), data)) => (hash, data),
+            _ => return Status::default(),
+        };
+
+        let mut roots: Vec<PathBuf> = data.prev_dirs.into_iter().collect();
+        roots.sort();
+        // Roots are relative to the outermost one, so the deepest is the nearest.
+        let root = roots
+            .iter()
+            .max_by_key(|dir| dir.components().count())
+            .and_then(|dir| dir.file_name())
+            .map(|name| name.to_string_lossy().to_string());
+
+        Status {
+            active: true,
+            root,
+            roots,
+            features: data.features,
+            hash: Some(hash.to_string()),
+            pinned: !data.pinned.is_empty(),
+            profile: data.profile,
+        }
+    }
+}
+
+pub fn run(cmd: PromptWidgetCmd) {
+    let status = Status::from_data(&Shadowenv::from_env());
+
+    if cmd.json {
+        println!("{}", serde_json::to_string(&status).unwrap());
+        return;
+    }
+
+    if status.active {
+        // A broken config shouldn't break the prompt: fall back to the defaults.
+        let config = Config::load().unwrap_or_default();
+        let template = cmd.format.or(config.prompt_widget.template.clone());
+        print!("{}", format_widget(&status, template.as_deref(), &config));
+    }
+}
+
+fn format_widget(status: &Status, template: Option<&str>, config: &Config) -> String {
+    let widget = match template {
+        Some(template) => {
+            let roots: Vec<String> = status
+                .roots
+                .iter()
+                .map(|d| d.display().to_string())
+                .collect();
+            let features: Vec<String> = status.features.iter().map(|f| f.to_string()).collect();
+
+            render_template(
+                template,
+                &[
+                    ("root", status.root.clone().unwrap_or_default()),
+                    ("roots", roots.join(", ")),
+                    ("features", features.join(",")),
+                    ("hash", status.hash.clone().unwrap_or_default()),
+                    (
+                        "pinned",
+                        if status.pinned { "pinned" } else { "" }.to_string(),
+                    ),
+                    ("profile", status.profile.clone().unwrap_or_default()),
+                ],
+            )
+        }
+        None if status.pinned => DEFAULT_PINNED_WIDGET.to_string(),
+        None => DEFAULT_WIDGET.to_string(),
+    };
+
+    if config.color() {
+        widget
+    } else {
+        strip_colors(&widget)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &str = r#"000000000000002a:{"scalars":[],"lists":[],"prev_dirs":["proj/sub","proj"],"features":[{"name":"node","version":null},{"name":"ruby","version":"3.3"}]}"#;
+
+    #[test]
+    fn inactive_status() {
+        assert_eq!(Status::from_data(""), Status::default());
+        assert_eq!(
+            Status::from_data(r#"0000000000000000:{"scalars":[],"lists":[],"prev_dirs":[]}"#),
+            Status::default()
+        );
+    }
+
+    #[test]
+    fn status_json() {
+        assert_eq!(
+            serde_json::to_string(&Status::from_data(DATA)).unwrap(),
+            r#"{"active":true,"root":"sub","roots":["proj","proj/sub"],"features":[{"name":"node","version":null},{"name":"ruby","version":"3.3"}],"hash":"000000000000002a","pinned":false}"#
+        );
+    }
+
+    #[test]
+    fn pinned_widget() {
+        let data = DATA.replace(
+            r#""features""#,
+            r#""pinned":["/src/proj/.shadowenv.d"],"features""#,
+        );
+        let status = Status::from_data(&data);
+        assert!(status.pinned);
+
+        let config = Config {
+            color: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(format_widget(&status, None, &config), "▒");
+        assert_eq!(
+            format_widget(&status, Some("{root} {pinned}"), &config),
+            "sub pinned"
+        );
+    }
+
+    #[test]
+    fn default_widget() {
+        let config = Config {
+            color: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(format_widget(&Status::from_data(DATA), None, &config), "░");
+    }
+
+    #[test]
+    fn widget_template() {
+        let status = Status::from_data(DATA);
+        assert_eq!(
+            format_widget(
+                &status,
+                Some("[{root} {features}@{hash}]"),
+                &Config::default()
+            ),
+            "[sub node,ruby:3.3@000000000000002a]"
+        );
+        assert_eq!(
+            format_widget(&status, Some("{roots}"), &Config::default()),
+            "proj, proj/sub"
+        );
     }
 }
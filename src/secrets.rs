@@ -0,0 +1,303 @@
+use crate::{hook::Modifications, shadowenv::Shadowenv, undo};
+use anyhow::{anyhow, Error};
+use blake2::{
+    digest::{consts::U8, KeyInit, Mac},
+    Blake2bMac,
+};
+use rand::{rngs::OsRng, RngCore};
+use regex::Regex;
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+};
+use thiserror::Error as ThisError;
+
+#[derive(ThisError, Debug)]
+pub enum SecretError {
+    #[error("refusing to read the secret in {}: it's readable by everyone (fix it with `chmod o-r`)", .0.display())]
+    WorldReadable(PathBuf),
+
+    #[error("unable to read the secret in {}: {}", .path.display(), .err)]
+    Unreadable { path: PathBuf, err: io::Error },
+
+    #[error("no secret command named `{0}` in the config (see `secrets.commands`)")]
+    UnknownCommand(String),
+
+    #[error("secret command `{name}` failed: {reason}")]
+    CommandFailed { name: String, reason: String },
+}
+
+/// Which variables hold secrets, from the `secrets.patterns` of the config: globs like `*_TOKEN`.
+#[derive(Debug, Default)]
+pub struct SecretMatcher {
+    patterns: Vec<Regex>,
+}
+
+impl SecretMatcher {
+    pub fn new(patterns: &[String]) -> SecretMatcher {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                let pattern = regex::escape(pattern).replace(r"\*", ".*");
+                Regex::new(&format!("^{}$", pattern)).expect("escaped glob is a valid regex")
+            })
+            .collect();
+        SecretMatcher { patterns }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.patterns.iter().any(|pattern| pattern.is_match(name))
+    }
+}
+
+type Key = [u8; 32];
+
+/// What's stored in `__shadowenv_data` instead of a secret, to tell whether it was changed.
+///
+/// Every child process can read `__shadowenv_data`, so the hash is keyed with a random per-user
+/// key: without it, guessable secrets could be brute-forced from their hash.
+pub fn hash(value: &str) -> String {
+    hash_with(key(), value)
+}
+
+fn hash_with(key: &Key, value: &str) -> String {
+    let mut mac = <Blake2bMac<U8> as KeyInit>::new_from_slice(key).expect("bad hasher key size");
+    mac.update(value.as_bytes());
+    let buf: [u8; 8] = mac.finalize().into_bytes().into();
+
+    format!("{:016x}", u64::from_be_bytes(buf))
+}
+
+fn key() -> &'static Key {
+    static KEY: OnceLock<Key> = OnceLock::new();
+    KEY.get_or_init(|| {
+        // Tests shouldn't leave a key in the home directory of whoever runs them.
+        let path = env::var("HOME")
+            .ok()
+            .filter(|_| !cfg!(test))
+            .map(|home| PathBuf::from(home).join(".config/shadowenv/hash-key"));
+        // Without a key file, hashes only match within this process: values look changed by the
+        // user, and are kept rather than undone, which is the safe side.
+        path.and_then(|path| load_or_generate_key(&path).ok())
+            .unwrap_or_else(generate_key)
+    })
+}
+
+fn generate_key() -> Key {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// Read the hash key from `path`, next to the trust key, creating it the first time.
+fn load_or_generate_key(path: &Path) -> Result<Key, Error> {
+    match fs::read(path) {
+        Ok(bytes) => bytes[..]
+            .try_into()
+            .map_err(|_| anyhow!("invalid key length in {}", path.display())),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            fs::create_dir_all(path.parent().unwrap())?;
+            // Write it aside and link it into place, so that shells starting at the same time
+            // never read half a key, and all end up with the same one.
+            let key = generate_key();
+            let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&tmp)?;
+            file.write_all(&key)?;
+            let linked = fs::hard_link(&tmp, path);
+            let _ = fs::remove_file(&tmp);
+            match linked {
+                Ok(()) => Ok(key),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => load_or_generate_key(path),
+                Err(err) => Err(err.into()),
+            }
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// What's shown instead of a secret: a fingerprint, so that values can still be told apart.
+pub fn redact(value: &str) -> String {
+    fingerprint(&hash(value))
+}
+
+fn fingerprint(hash: &str) -> String {
+    format!("[redacted:{}]", &hash[..8])
+}
+
+/// Redact the values of the secret scalars of some undo data.
+pub fn redact_data(data: &mut undo::Data, matcher: &SecretMatcher) {
+    for scalar in data.scalars.iter_mut() {
+        if scalar.secret || matcher.matches(&scalar.name) {
+            scalar.original = scalar.original.as_deref().map(redact);
+            scalar.current = match &scalar.current_hash {
+                Some(hash) => Some(fingerprint(hash)),
+                None => scalar.current.as_deref().map(redact),
+            };
+        }
+    }
+}
+
+/// Read a secret from a file, as `secret/file` does. Files anyone can read aren't secret anymore.
+pub fn read_file(path: &Path) -> Result<String, SecretError> {
+    let unreadable = |err| SecretError::Unreadable {
+        path: path.to_path_buf(),
+        err,
+    };
+
+    let metadata = fs::metadata(path).map_err(unreadable)?;
+    if metadata.permissions().mode() & 0o004 != 0 {
+        return Err(SecretError::WorldReadable(path.to_path_buf()));
+    }
+    let contents = fs::read_to_string(path).map_err(unreadable)?;
+    Ok(trim_newline(contents))
+}
+
+/// Get a secret from the output of a command, as `secret/command` does: `argv` is the command
+/// configured as `name` in `secrets.commands`.
+pub fn run_command(name: &str, argv: Option<&[String]>) -> Result<String, SecretError> {
+    let failed = |reason: String| SecretError::CommandFailed {
+        name: name.to_string(),
+        reason,
+    };
+
+    let (program, args) = argv
+        .ok_or_else(|| SecretError::UnknownCommand(name.to_string()))?
+        .split_first()
+        .ok_or_else(|| failed("the command is empty".to_string()))?;
+
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|err| failed(err.to_string()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(failed(format!("{}: {}", output.status, stderr.trim())));
+    }
+
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|_| failed("the output isn't valid UTF-8".to_string()))?;
+    Ok(trim_newline(stdout))
+}
+
+fn trim_newline(mut value: String) -> String {
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+    value
+}
+
+/// Redact the modifications loading `shadowenv`, for display: the secrets it set, the variables
+/// matching `matcher`, and their values in `__shadowenv_data`.
+pub fn redact_modifications(
+    modifications: &mut Modifications,
+    shadowenv: &Shadowenv,
+    matcher: &SecretMatcher,
+) -> Result<(), Error> {
+    for (name, value) in modifications.exported.iter_mut() {
+        if name == "__shadowenv_data" {
+            if let Some(value) = value {
+                let (hash, mut data) = undo::parse(value)?;
+                redact_data(&mut data, matcher);
+                *value = undo::format(hash, &data)?;
+            }
+        } else if shadowenv.is_secret(name) || matcher.matches(name) {
+            *value = value.as_deref().map(redact);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matcher() {
+        let matcher = SecretMatcher::new(&["*_TOKEN".to_string(), "AWS_*".to_string()]);
+        assert!(matcher.matches("GITHUB_TOKEN"));
+        assert!(matcher.matches("AWS_SECRET_ACCESS_KEY"));
+        assert!(!matcher.matches("TOKEN_FILE"));
+        assert!(!matcher.matches("PATH"));
+        assert!(!SecretMatcher::default().matches("GITHUB_TOKEN"));
+    }
+
+    #[test]
+    fn test_read_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("token");
+        fs::write(&path, "t0ken\n").unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(read_file(&path).unwrap(), "t0ken");
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(
+            read_file(&path),
+            Err(SecretError::WorldReadable(_))
+        ));
+    }
+
+    #[test]
+    fn test_run_command() {
+        let argv = ["echo".to_string(), "t0ken".to_string()];
+        assert_eq!(run_command("echo", Some(&argv)).unwrap(), "t0ken");
+
+        assert!(matches!(
+            run_command("nope", None),
+            Err(SecretError::UnknownCommand(_))
+        ));
+        let argv = ["false".to_string()];
+        assert!(matches!(
+            run_command("false", Some(&argv)),
+            Err(SecretError::CommandFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_hash_is_keyed() {
+        // The plain 64-bit Blake2b digest of "hunter2".
+        assert_ne!(hash("hunter2"), "2fd08828091876df");
+        assert_eq!(hash("hunter2"), hash("hunter2"));
+        assert_ne!(
+            hash_with(&[1; 32], "hunter2"),
+            hash_with(&[2; 32], "hunter2")
+        );
+    }
+
+    #[test]
+    fn test_load_or_generate_key() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("shadowenv/hash-key");
+
+        let key = load_or_generate_key(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(load_or_generate_key(&path).unwrap(), key);
+
+        fs::write(&path, "short").unwrap();
+        assert!(load_or_generate_key(&path).is_err());
+    }
+
+    #[test]
+    fn test_redact() {
+        let redacted = redact("hunter2");
+        assert!(redacted.starts_with("[redacted:"));
+        assert!(!redacted.contains("hunter2"));
+        assert_eq!(redacted, redact("hunter2"));
+        assert_ne!(redacted, redact("hunter3"));
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{features::Feature, undo};
+use crate::{
+    features::{self, Feature, FeatureError, Requirement},
+    hash::Hash,
+    secrets, undo,
+};
 use anyhow::Error;
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
@@ -20,9 +24,25 @@ pub struct Shadowenv {
     lists: HashSet<String>,
     /// list of features provided by all plugins
     features: HashSet<Feature>,
+    /// features provided by the previously active shadowenv
+    prev_features: HashSet<Feature>,
+    /// features that must not be provided, as declared with `conflicts`
+    conflicts: Vec<Requirement>,
     target_hash: u64,
     prev_dirs: HashSet<PathBuf>,
     current_dirs: HashSet<PathBuf>,
+    /// the roots pinned with `shadowenv pin`, carried over from $__shadowenv_data
+    pinned: Vec<PathBuf>,
+    /// the profile the programs were loaded with
+    profile: Option<String>,
+    /// variables set with `env/set-secret`, or to values from `secret/*` builtins
+    secrets: HashSet<String>,
+    /// the values returned by `secret/*` builtins
+    secret_values: HashSet<String>,
+    /// whether to keep only hashes of all current values in $__shadowenv_data, not just secrets
+    hash_values: bool,
+    /// variables `shadowenv exec` must not pass on, as declared with `env/no-propagate`
+    no_propagate: HashSet<String>,
 }
 
 impl Shadowenv {
@@ -36,6 +56,8 @@ impl Shadowenv {
         target_hash: u64,
         clobber: bool,
     ) -> Shadowenv {
+        let prev_features = shadowenv_data.features.iter().cloned().collect();
+        let pinned = shadowenv_data.pinned.clone();
         let (unshadowed_env, no_clobber, prev_dirs) =
             Shadowenv::unshadow(&env, shadowenv_data, clobber);
 
@@ -46,9 +68,17 @@ impl Shadowenv {
             initial_env: env,
             lists: HashSet::new(),
             features: HashSet::new(),
+            prev_features,
+            conflicts: vec![],
             target_hash,
             prev_dirs,
             current_dirs: HashSet::new(),
+            pinned,
+            profile: None,
+            secrets: HashSet::new(),
+            secret_values: HashSet::new(),
+            hash_values: false,
+            no_propagate: HashSet::new(),
         }
     }
 
@@ -66,7 +96,11 @@ impl Shadowenv {
             }
 
             let current_value = env_get(&result, scalar.name.clone());
-            if current_value == scalar.current {
+            let unchanged = match &scalar.current_hash {
+                Some(hash) => current_value.as_deref().map(secrets::hash).as_ref() == Some(hash),
+                None => current_value == scalar.current,
+            };
+            if unchanged {
                 env_set(&mut result, scalar.name, scalar.original);
             } else if !clobber {
                 no_clobber.insert(scalar.name);
@@ -74,13 +108,8 @@ impl Shadowenv {
         }
         // TODO: no_clobber for lists
         for list in shadowenv_data.lists {
-            for addition in list.additions {
-                env_remove_from_pathlist(&mut result, list.name.clone(), addition);
-            }
-            // TODO(burke): figure out a way to preserve approximate ordering
-            for deletion in list.deletions {
-                env_prepend_to_pathlist(&mut result, list.name.clone(), deletion);
-            }
+            let unshadowed = unshadow_pathlist(result.get(&list.name).map(String::as_str), &list);
+            env_set(&mut result, list.name, unshadowed);
         }
         (result, no_clobber, shadowenv_data.prev_dirs)
     }
@@ -118,17 +147,68 @@ impl Shadowenv {
                     no_clobber = true;
                 }
 
-                data.add_scalar(varname, unshadowed_value, final_value, no_clobber);
+                let secret = self.secrets.contains(&varname);
+                if secret || self.hash_values {
+                    let current_hash = final_value.as_deref().map(secrets::hash);
+                    data.add_hashed_scalar(
+                        varname,
+                        unshadowed_value,
+                        current_hash,
+                        no_clobber,
+                        secret,
+                    );
+                } else {
+                    data.add_scalar(varname, unshadowed_value, final_value, no_clobber, false);
+                }
             }
         }
         data.prev_dirs = self.current_dirs.clone();
+        data.features = self.features.iter().cloned().collect();
+        data.features.sort();
+        data.pinned = self.pinned.clone();
+        data.profile = self.profile.clone();
+
+        data
+    }
+
+    /// Describe how to get from the final env of `from` to the final env of `to`, as if `to` had
+    /// been activated on top of `from`. Variables either of them treats as a pathlist are diffed
+    /// entry by entry.
+    pub fn compare(from: &Shadowenv, to: &Shadowenv) -> undo::Data {
+        let mut varnames: Vec<&String> = from.env.keys().chain(to.env.keys()).collect();
+        varnames.sort();
+        varnames.dedup();
+
+        let mut data = undo::Data::new();
+        for varname in varnames {
+            let a = from.env.get(varname);
+            let b = to.env.get(varname);
+            if a == b {
+                continue;
+            }
+
+            if from.lists.contains(varname) || to.lists.contains(varname) {
+                let from_parts: Vec<&str> = a.map(|s| s.split(':').collect()).unwrap_or_default();
+                let to_parts: Vec<&str> = b.map(|s| s.split(':').collect()).unwrap_or_default();
+                let (additions, deletions) = diff_vecs(from_parts, to_parts);
+                data.add_list(varname.clone(), additions, deletions);
+            } else {
+                let secret = from.is_secret(varname) || to.is_secret(varname);
+                data.add_scalar(varname.clone(), a.cloned(), b.cloned(), false, secret);
+            }
+        }
+        data.prev_dirs = to.current_dirs.clone();
 
         data
     }
 
     fn format_shadowenv_data(&self) -> Result<String, Error> {
-        let d = self.shadowenv_data();
-        Ok(format!("{:016x}:", self.target_hash,) + &serde_json::to_string(&d)?)
+        undo::format(
+            Some(Hash {
+                hash: self.target_hash,
+            }),
+            &self.shadowenv_data(),
+        )
     }
 
     pub fn exports(&self) -> Result<HashMap<String, Option<String>>, Error> {
@@ -155,9 +235,51 @@ impl Shadowenv {
     }
 
     pub fn set(&mut self, a: &str, b: Option<&str>) {
+        if b.is_some_and(|value| self.contains_secret(value)) {
+            self.secrets.insert(a.to_string());
+        }
         env_set(&mut self.env, a.to_string(), b.map(|s| s.to_string()))
     }
 
+    /// Set a variable like [Shadowenv::set], but mark its value as a secret.
+    pub fn set_secret(&mut self, a: &str, b: Option<&str>) {
+        self.secrets.insert(a.to_string());
+        self.set(a, b)
+    }
+
+    /// Remember a value as a secret: variables set to anything containing it, like a URL with the
+    /// secret as its password, are treated as if set with [Shadowenv::set_secret].
+    pub fn add_secret_value(&mut self, value: &str) {
+        self.secret_values.insert(value.to_string());
+    }
+
+    fn contains_secret(&self, value: &str) -> bool {
+        self.secret_values
+            .iter()
+            .any(|secret| !secret.is_empty() && value.contains(secret.as_str()))
+    }
+
+    pub fn set_hash_values(&mut self, hash_values: bool) {
+        self.hash_values = hash_values;
+    }
+
+    pub fn is_secret(&self, varname: &str) -> bool {
+        self.secrets.contains(varname)
+    }
+
+    pub fn add_no_propagate(&mut self, varname: &str) {
+        self.no_propagate.insert(varname.to_string());
+    }
+
+    pub fn no_propagate(&self) -> &HashSet<String> {
+        &self.no_propagate
+    }
+
+    /// The final state of the env, after running all programs.
+    pub fn env(&self) -> &HashMap<String, String> {
+        &self.env
+    }
+
     pub fn get(&self, a: &str) -> Option<String> {
         env_get(&self.env, a.to_string())
     }
@@ -182,15 +304,73 @@ impl Shadowenv {
         env_prepend_to_pathlist(&mut self.env, a.to_string(), b.to_string())
     }
 
-    pub fn add_feature(&mut self, name: &str, version: Option<&str>) {
+    pub fn add_feature(&mut self, name: &str, version: Option<&str>) -> Result<(), FeatureError> {
         let feature = Feature::new(name.to_string(), version.map(|s| s.to_string()));
+        if let Some(conflict) = self.conflicts.iter().find(|c| c.matches(&feature)) {
+            return Err(conflict.conflict(&feature));
+        }
         self.features.insert(feature);
+        Ok(())
+    }
+
+    /// Whether a feature matching the requirement has been provided so far.
+    pub fn has_feature(&self, requirement: &Requirement) -> bool {
+        self.features.iter().any(|f| requirement.matches(f))
+    }
+
+    /// The version of a feature provided so far, the highest one if it was provided several times.
+    /// None if it wasn't provided, or only without a version.
+    pub fn feature_version(&self, name: &str) -> Option<&str> {
+        self.features
+            .iter()
+            .filter(|f| f.name() == name)
+            .filter_map(Feature::version)
+            .max_by(|a, b| features::compare_versions(a, b))
+    }
+
+    /// Fail unless a feature matching the requirement has been provided so far.
+    pub fn require_feature(&self, requirement: &Requirement) -> Result<(), FeatureError> {
+        if self.has_feature(requirement) {
+            Ok(())
+        } else {
+            Err(requirement.missing())
+        }
+    }
+
+    /// Fail if a feature matching the requirement has been provided so far, and from now on, if one
+    /// is provided later.
+    pub fn add_conflict(&mut self, requirement: Requirement) -> Result<(), FeatureError> {
+        if let Some(feature) = self.features.iter().find(|f| requirement.matches(f)) {
+            return Err(requirement.conflict(feature));
+        }
+        self.conflicts.push(requirement);
+        Ok(())
     }
 
     pub fn features(&self) -> HashSet<Feature> {
         self.features.iter().cloned().collect()
     }
 
+    pub fn prev_features(&self) -> HashSet<Feature> {
+        self.prev_features.iter().cloned().collect()
+    }
+
+    pub fn target_hash(&self) -> u64 {
+        self.target_hash
+    }
+
+    pub fn set_profile(&mut self, profile: Option<String>) {
+        self.profile = profile;
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    pub fn pinned(&self) -> bool {
+        !self.pinned.is_empty()
+    }
+
     pub fn current_dirs(&self) -> HashSet<PathBuf> {
         self.current_dirs.iter().cloned().collect()
     }
@@ -292,6 +472,23 @@ fn env_prepend_to_pathlist(env: &mut HashMap<String, String>, a: String, b: Stri
     env.insert(a, next);
 }
 
+/// The value `list`'s pathlist had before its additions and deletions were applied, as far as it
+/// can be reconstructed from its `current` value.
+pub fn unshadow_pathlist(current: Option<&str>, list: &undo::List) -> Option<String> {
+    let mut env = HashMap::new();
+    if let Some(current) = current {
+        env.insert(list.name.clone(), current.to_string());
+    }
+    for addition in &list.additions {
+        env_remove_from_pathlist(&mut env, list.name.clone(), addition.clone());
+    }
+    // TODO(burke): figure out a way to preserve approximate ordering
+    for deletion in &list.deletions {
+        env_prepend_to_pathlist(&mut env, list.name.clone(), deletion.clone());
+    }
+    env.remove(&list.name)
+}
+
 fn diff_vecs(oldvec: Vec<&str>, newvec: Vec<&str>) -> (Vec<String>, Vec<String>) {
     let mut delta: HashMap<&str, i32> = HashMap::new();
 
@@ -355,6 +552,45 @@ mod tests {
         assert_eq!(shadowenv.get("field1"), Some("v1".to_string()))
     }
 
+    #[test]
+    fn test_unshadow_secrets() {
+        let mut data = Data::new();
+        data.add_hashed_scalar(
+            "API_TOKEN".to_string(),
+            Some("old".to_string()),
+            Some(secrets::hash("t0ken")),
+            false,
+            true,
+        );
+
+        // The secret is still there: undo it.
+        let shadowenv = build_shadow_env(vec![("API_TOKEN", "t0ken")], data.clone());
+        assert_eq!(shadowenv.get("API_TOKEN"), Some("old".to_string()));
+
+        // It was changed since: leave it alone.
+        let shadowenv = build_shadow_env(vec![("API_TOKEN", "changed")], data);
+        assert_eq!(shadowenv.get("API_TOKEN"), Some("changed".to_string()));
+    }
+
+    #[test]
+    fn test_hash_values() {
+        let mut shadowenv = build_shadow_env(vec![("RAILS_ENV", "development")], Data::new());
+        shadowenv.set_hash_values(true);
+        shadowenv.set("RAILS_ENV", Some("test"));
+
+        let data = shadowenv.shadowenv_data();
+        let scalar = &data.scalars[0];
+        assert_eq!(scalar.original, Some("development".to_string()));
+        assert_eq!(scalar.current, None);
+        assert_eq!(scalar.current_hash, Some(secrets::hash("test")));
+        // Not the plain Blake2b digest of "test", which anyone could check guesses against.
+        assert_ne!(scalar.current_hash, Some("96ad3bb4a2d666d3".to_string()));
+        assert!(!scalar.secret);
+
+        let shadowenv = build_shadow_env(vec![("RAILS_ENV", "test")], data);
+        assert_eq!(shadowenv.get("RAILS_ENV"), Some("development".to_string()));
+    }
+
     #[test]
     fn test_shadowenv_data() {
         let mut shadowenv = build_shadow_env(
@@ -376,18 +612,24 @@ mod tests {
                     original: Some("v0".to_string()),
                     current: Some("v2".to_string()),
                     no_clobber: false,
+                    secret: false,
+                    current_hash: None,
                 },
                 Scalar {
                     name: "VAR_B".to_string(),
                     original: Some("v0".to_string()),
                     current: None,
                     no_clobber: false,
+                    secret: false,
+                    current_hash: None,
                 },
                 Scalar {
                     name: "VAR_C".to_string(),
                     original: None,
                     current: Some("v3".to_string()),
                     no_clobber: false,
+                    secret: false,
+                    current_hash: None,
                 },
             ],
             lists: vec![List {
@@ -396,6 +638,10 @@ mod tests {
                 deletions: vec!["/path1".to_string()],
             }],
             prev_dirs: Default::default(),
+            features: vec![],
+            pinned: vec![],
+            profile: None,
+            session: None,
         };
 
         let expected_formatted_data = r#"00000000075bcd15:{"scalars":[{"name":"VAR_A","original":"v0","current":"v2","no_clobber":false},{"name":"VAR_B","original":"v0","current":null,"no_clobber":false},{"name":"VAR_C","original":null,"current":"v3","no_clobber":false}],"lists":[{"name":"PATH","additions":["/path3","/path4"],"deletions":["/path1"]}],"prev_dirs":[]}"#;
@@ -422,4 +668,57 @@ mod tests {
 
         assert_eq!(shadowenv.exports().unwrap(), expected_export);
     }
+
+    #[test]
+    fn test_compare() {
+        let mut from = build_shadow_env(
+            vec![("VAR_A", "v0"), ("PATH", "/path1:/path2")],
+            Default::default(),
+        );
+        from.set("VAR_A", Some("v1"));
+        from.set("VAR_B", Some("from"));
+        from.prepend_to_pathlist("PATH", "/from");
+
+        let mut to = build_shadow_env(
+            vec![("VAR_A", "v0"), ("PATH", "/path1:/path2")],
+            Default::default(),
+        );
+        to.set("VAR_A", Some("v1"));
+        to.set("VAR_C", Some("to"));
+        to.remove_from_pathlist("PATH", "/path1");
+        to.prepend_to_pathlist("PATH", "/to");
+
+        let expected = Data {
+            scalars: vec![
+                Scalar {
+                    name: "VAR_B".to_string(),
+                    original: Some("from".to_string()),
+                    current: None,
+                    no_clobber: false,
+                    secret: false,
+                    current_hash: None,
+                },
+                Scalar {
+                    name: "VAR_C".to_string(),
+                    original: None,
+                    current: Some("to".to_string()),
+                    no_clobber: false,
+                    secret: false,
+                    current_hash: None,
+                },
+            ],
+            lists: vec![List {
+                name: "PATH".to_string(),
+                additions: vec!["/to".to_string()],
+                deletions: vec!["/from".to_string(), "/path1".to_string()],
+            }],
+            prev_dirs: Default::default(),
+            features: vec![],
+            pinned: vec![],
+            profile: None,
+            session: None,
+        };
+
+        assert_eq!(Shadowenv::compare(&from, &to), expected);
+    }
 }
@@ -0,0 +1,198 @@
+use crate::{hash::Hash, output, secrets, undo, xdg};
+use anyhow::Error;
+use std::{
+    fs,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Marks a `__shadowenv_data` whose data is kept in a session file: `<hash>:@<session>`.
+pub const SESSION_PREFIX: &str = "@";
+
+/// How many session files a shell keeps: the most recently used ones, since processes it started
+/// may still point to them until they claim their own.
+const SESSIONS_PER_SHELL: usize = 8;
+
+/// The pid of the shell owning `session`, named `<pid>-<hash of the data>`, or None if it isn't a
+/// valid session name. Anything else could be a path from an environment we don't control.
+pub fn session_pid(session: &str) -> Option<i32> {
+    let (pid, id) = session.split_once('-')?;
+    let valid = !pid.is_empty()
+        && pid.bytes().all(|b| b.is_ascii_digit())
+        && !id.is_empty()
+        && id.bytes().all(|b| b.is_ascii_hexdigit());
+    valid.then(|| pid.parse().ok()).flatten()
+}
+
+fn session_file(dir: &Path, session: &str) -> PathBuf {
+    dir.join(format!("{}.json", session))
+}
+
+/// Read the undo data of `session`. None if the file is gone (e.g. the runtime dir was cleared on
+/// logout, or the shell owning it exited before a child shell could claim it), in which case the
+/// active shadowenv can't be undone.
+pub fn read(session: &str) -> Option<String> {
+    session_pid(session)?;
+    fs::read_to_string(session_file(&xdg::runtime_dir().ok()?, session)).ok()
+}
+
+/// Write the undo data of a shadowenv to a session file owned by `owner`, and return the
+/// `__shadowenv_data` value pointing to it.
+///
+/// Session files are never changed once written: they're named after their contents, since
+/// processes started from the shell inherit the pointer to them. Shells claim the session they
+/// inherited by storing it again under their own pid, so only those of exited shells are removed,
+/// along with all but the last few of each shell.
+pub fn store(hash: Option<Hash>, data: &undo::Data, owner: u32) -> Result<String, Error> {
+    store_in(&xdg::runtime_dir()?, hash, data, owner)
+}
+
+fn store_in(
+    dir: &Path,
+    hash: Option<Hash>,
+    data: &undo::Data,
+    owner: u32,
+) -> Result<String, Error> {
+    let json = serde_json::to_string(data)?;
+    let session = format!("{}-{}", owner, secrets::hash(&json));
+
+    let path = session_file(dir, &session);
+    if path.exists() {
+        // Back to a state it was in before: it's the most recently used one again.
+        fs::File::open(&path)?.set_modified(SystemTime::now())?;
+    } else {
+        write(&path, &json)?;
+    }
+    let _ = clean_up_sessions(dir, owner, &path);
+
+    Ok(format!(
+        "{:016x}:{}{}",
+        hash.map_or(0, |hash| hash.hash),
+        SESSION_PREFIX,
+        session
+    ))
+}
+
+/// Write through a temporary file, so a shell never reads half-written data.
+fn write(path: &Path, contents: &str) -> Result<(), Error> {
+    let tmp = path.with_extension("json.tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Remove the session files of shells that exited, and the least recently used ones of `owner`
+/// past [SESSIONS_PER_SHELL], always keeping `current`.
+fn clean_up_sessions(dir: &Path, owner: u32, current: &Path) -> Result<(), Error> {
+    let mut owned = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let pid = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(session_pid);
+        match pid {
+            Some(pid) if pid == owner as i32 && path != current => {
+                owned.push((entry.metadata()?.modified()?, path));
+            }
+            Some(pid) if pid != owner as i32 && !output::process_alive(pid) => {
+                let _ = fs::remove_file(path);
+            }
+            _ => (),
+        }
+    }
+
+    owned.sort();
+    let stale = (owned.len() + 1).saturating_sub(SESSIONS_PER_SHELL);
+    for (_, path) in owned.into_iter().take(stale) {
+        let _ = fs::remove_file(path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_pid() {
+        assert_eq!(session_pid("1234-00c0ffee"), Some(1234));
+        assert_eq!(session_pid("../../etc/passwd"), None);
+        assert_eq!(session_pid("1234-00c0ffee/.."), None);
+        assert_eq!(session_pid("1234"), None);
+    }
+
+    #[test]
+    fn test_sessions_are_immutable() {
+        let dir = tempfile::tempdir().unwrap();
+        let owner = std::process::id();
+        let hash = || Some(Hash { hash: 42 });
+        let mut data = undo::Data::new();
+
+        let first = store_in(dir.path(), hash(), &data, owner).unwrap();
+        assert_eq!(store_in(dir.path(), hash(), &data, owner).unwrap(), first);
+
+        data.profile = Some("ci".to_string());
+        let second = store_in(dir.path(), hash(), &data, owner).unwrap();
+        assert_ne!(second, first);
+
+        // Whoever inherited the first one can still read it.
+        let session = |value: &str| value.split_once(":@").unwrap().1.to_string();
+        let read = |value: &str| fs::read_to_string(session_file(dir.path(), &session(value)));
+        assert_eq!(
+            read(&first).unwrap(),
+            r#"{"scalars":[],"lists":[],"prev_dirs":[]}"#
+        );
+        assert!(read(&second).unwrap().contains(r#""profile":"ci""#));
+        assert_eq!(session_pid(&session(&second)), Some(owner as i32));
+    }
+
+    #[test]
+    fn test_clean_up_dead_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let alive = session_file(dir.path(), &format!("{}-00c0ffee", std::process::id()));
+        // pid_max is at most 2^22 on Linux.
+        let dead = session_file(dir.path(), &format!("{}-00c0ffee", i32::MAX));
+        let other = dir.path().join("daemon.sock");
+        for path in [&alive, &dead, &other] {
+            write(path, "{}").unwrap();
+        }
+
+        clean_up_sessions(dir.path(), 1, &other).unwrap();
+        assert!(alive.exists());
+        assert!(!dead.exists());
+        assert!(other.exists());
+    }
+
+    #[test]
+    fn test_sessions_per_shell() {
+        let dir = tempfile::tempdir().unwrap();
+        let owner = std::process::id();
+        let mut data = undo::Data::new();
+
+        let mut last = String::new();
+        for n in 0..SESSIONS_PER_SHELL + 2 {
+            data.profile = Some(n.to_string());
+            last = store_in(dir.path(), None, &data, owner).unwrap();
+        }
+
+        assert_eq!(
+            fs::read_dir(dir.path()).unwrap().count(),
+            SESSIONS_PER_SHELL
+        );
+        let session = last.split_once(":@").unwrap().1;
+        assert!(session_file(dir.path(), session).exists());
+    }
+}
@@ -1,8 +1,24 @@
-use anyhow::Error;
+use crate::{features::Feature, hash::Hash, state};
+use anyhow::{anyhow, Error};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 use serde_derive::{Deserialize, Serialize};
-use std::{collections::HashSet, path::PathBuf, result::Result};
+use std::{
+    collections::HashSet,
+    io::{Read, Write},
+    path::PathBuf,
+    result::Result,
+    str::FromStr,
+};
+use thiserror::Error as ThisError;
 
-#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+/// Marks the compact encoding of the data: base64 of the deflated JSON.
+const COMPACT_PREFIX: &str = "v3:";
+/// Above this size, the JSON is compacted: it's exported to every process, and counts towards the
+/// ARG_MAX limit of every command line.
+const COMPACT_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct Scalar {
     #[serde(default)]
     pub name: String,
@@ -12,9 +28,17 @@ pub struct Scalar {
     pub current: Option<String>,
     #[serde(default)]
     pub no_clobber: bool,
+    /// Set with `env/set-secret`: the value is redacted wherever shadowenv displays it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub secret: bool,
+    /// Stored instead of `current` for secrets (or every value with `secrets.hash_values` in the
+    /// config), which are then never written down in plaintext: when the shadowenv is loaded
+    /// again, its programs are re-evaluated to get them back.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_hash: Option<String>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct List {
     #[serde(default)]
     pub name: String,
@@ -24,7 +48,7 @@ pub struct List {
     pub deletions: Vec<String>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct Data {
     #[serde(default)]
     pub scalars: Vec<Scalar>,
@@ -32,6 +56,19 @@ pub struct Data {
     pub lists: Vec<List>,
     #[serde(default)]
     pub prev_dirs: HashSet<PathBuf>,
+    /// Features provided by the active shadowenv, so they can be shown without re-running it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<Feature>,
+    /// The roots set by `shadowenv pin`: while there are any, they're loaded instead of the
+    /// shadowenv of the current directory.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned: Vec<PathBuf>,
+    /// The profile the active shadowenv was loaded with, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// The session file this data was read from, with `state = "file"`.
+    #[serde(skip)]
+    pub session: Option<String>,
 }
 
 impl Data {
@@ -45,6 +82,10 @@ impl Data {
             scalars: vec![],
             lists: vec![],
             prev_dirs: HashSet::new(),
+            features: vec![],
+            pinned: vec![],
+            profile: None,
+            session: None,
         }
     }
 
@@ -54,12 +95,34 @@ impl Data {
         original: Option<String>,
         current: Option<String>,
         no_clobber: bool,
+        secret: bool,
     ) {
         self.scalars.push(Scalar {
             name,
             original,
             current,
             no_clobber,
+            secret,
+            current_hash: None,
+        })
+    }
+
+    /// Add a scalar of which only the hash of the current value is kept.
+    pub fn add_hashed_scalar(
+        &mut self,
+        name: String,
+        original: Option<String>,
+        current_hash: Option<String>,
+        no_clobber: bool,
+        secret: bool,
+    ) {
+        self.scalars.push(Scalar {
+            name,
+            original,
+            current: None,
+            no_clobber,
+            secret,
+            current_hash,
         })
     }
 
@@ -71,3 +134,139 @@ impl Data {
         })
     }
 }
+
+/// Split a `__shadowenv_data` value into the hash of the active shadowenv, if there is one, and the
+/// data needed to undo it.
+/// Both `<hash>:<json>` and the compact `<hash>:v3:<base64>` are understood, as well as
+/// `<hash>:@<session>` when the data is kept in a session file.
+pub fn parse(shadowenv_data: &str) -> Result<(Option<Hash>, Data), Error> {
+    try_parse(shadowenv_data).map_err(|err| {
+        CorruptData {
+            reason: err.to_string(),
+        }
+        .into()
+    })
+}
+
+/// `__shadowenv_data` that can't be parsed: truncated, say, or mangled by a tool quoting it.
+#[derive(ThisError, Debug)]
+#[error("`__shadowenv_data` is corrupt ({reason})")]
+pub struct CorruptData {
+    pub reason: String,
+}
+
+fn try_parse(shadowenv_data: &str) -> Result<(Option<Hash>, Data), Error> {
+    let mut parts = shadowenv_data.splitn(2, ':');
+    let hash = match parts.next() {
+        None | Some("") | Some("0000000000000000") => None,
+        Some(x) => Some(Hash::from_str(x)?),
+    };
+    let data = match parts.next() {
+        Some(data) if data.starts_with(state::SESSION_PREFIX) => {
+            let session = &data[state::SESSION_PREFIX.len()..];
+            match state::read(session) {
+                Some(json) => Data {
+                    session: Some(session.to_string()),
+                    ..Data::from_str(&json)?
+                },
+                // The data is lost, which is as bad as if it were corrupt.
+                None => return Err(anyhow!("its session file {} is gone", session)),
+            }
+        }
+        Some(data) => match data.strip_prefix(COMPACT_PREFIX) {
+            Some(compact) => Data::from_str(&decompress(compact)?)?,
+            None => Data::from_str(data)?,
+        },
+        None => Data::new(),
+    };
+    Ok((hash, data))
+}
+
+/// The inverse of [parse]: format a `__shadowenv_data` value, compacted if it's large.
+pub fn format(hash: Option<Hash>, data: &Data) -> Result<String, Error> {
+    let hash = hash.map_or(0, |hash| hash.hash);
+    let json = serde_json::to_string(data)?;
+    if json.len() > COMPACT_THRESHOLD {
+        let compact = compress(&json)?;
+        if compact.len() + COMPACT_PREFIX.len() < json.len() {
+            return Ok(format!("{:016x}:{}{}", hash, COMPACT_PREFIX, compact));
+        }
+    }
+    Ok(format!("{:016x}:{}", hash, json))
+}
+
+fn compress(json: &str) -> Result<String, Error> {
+    let mut encoder = DeflateEncoder::new(vec![], Compression::best());
+    encoder.write_all(json.as_bytes())?;
+    Ok(STANDARD_NO_PAD.encode(encoder.finish()?))
+}
+
+fn decompress(compact: &str) -> Result<String, Error> {
+    let deflated = STANDARD_NO_PAD.decode(compact)?;
+    let mut json = String::new();
+    DeflateDecoder::new(deflated.as_slice()).read_to_string(&mut json)?;
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn large_data() -> Data {
+        let mut data = Data::new();
+        let additions = (0..100).map(|i| format!("/opt/tool-{}/bin", i)).collect();
+        data.add_list("PATH".to_string(), additions, vec![]);
+        data.add_scalar(
+            "GEM_HOME".to_string(),
+            None,
+            Some("/home/user/.gem".to_string()),
+            false,
+            false,
+        );
+        data
+    }
+
+    #[test]
+    fn test_small_data_is_json() {
+        let formatted = format(Some(Hash { hash: 42 }), &Data::new()).unwrap();
+        assert_eq!(
+            formatted,
+            r#"000000000000002a:{"scalars":[],"lists":[],"prev_dirs":[]}"#
+        );
+    }
+
+    #[test]
+    fn test_large_data_is_compacted() {
+        let data = large_data();
+        let formatted = format(Some(Hash { hash: 42 }), &data).unwrap();
+        assert!(formatted.starts_with("000000000000002a:v3:"));
+        assert!(formatted.len() < serde_json::to_string(&data).unwrap().len());
+
+        let (hash, parsed) = parse(&formatted).unwrap();
+        assert_eq!(hash, Some(Hash { hash: 42 }));
+        assert_eq!(parsed, data);
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let json = serde_json::to_string(&large_data()).unwrap();
+        let (hash, parsed) = parse(&format!("000000000000002a:{}", json)).unwrap();
+        assert_eq!(hash, Some(Hash { hash: 42 }));
+        assert_eq!(parsed, large_data());
+
+        assert_eq!(parse("").unwrap(), (None, Data::new()));
+    }
+
+    #[test]
+    fn test_parse_corrupt() {
+        for corrupt in [
+            "000000000000002a:v3:not base64!",
+            r#"000000000000002a:{"scalars":[{"name":"A","orig"#,
+            r#"0000000000002a:{"scalars":[]}"#,
+            "not a hash",
+        ] {
+            let err = parse(corrupt).unwrap_err();
+            assert!(err.is::<CorruptData>(), "{}: {}", corrupt, err);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::{
+    cli::WatchCmd,
+    config::Config,
+    get_current_dir_or_exit,
+    hook::{self, Modifications, VariableOutputMode},
+    loader,
+    shadowenv::Shadowenv,
+    undo,
+};
+use anyhow::{anyhow, Error};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::HashSet,
+    env,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+/// Editors tend to write files in several steps (truncate, write, rename...), so wait for things
+/// to settle before reloading.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Print a hook payload for `cmd.dir`, then another one every time the hash of its sources changes.
+pub fn run(cmd: WatchCmd) -> Result<(), Error> {
+    let dir = cmd
+        .dir
+        .map(PathBuf::from)
+        .unwrap_or_else(get_current_dir_or_exit)
+        .canonicalize()?;
+
+    let vars = env::vars().collect();
+    if hook::is_disabled(&vars) {
+        return Err(anyhow!(
+            "shadowenv is deactivated in this shell: run `shadowenv activate` first"
+        ));
+    }
+
+    let config = Config::load()?;
+    let profile = loader::selected_profile(&vars).map(str::to_string);
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    let mut watched: HashSet<PathBuf> = HashSet::new();
+    // The hash of the sources the last payload was printed for. None until a payload was printed
+    // successfully, so that errors (e.g. an untrusted shadowenv) are retried on the next change.
+    let mut emitted: Option<Option<u64>> = None;
+
+    loop {
+        let roots = loader::find_shadowenv_paths(&dir).unwrap_or_default();
+        update_watches(
+            &mut watcher,
+            &mut watched,
+            watch_paths(&dir, &roots, profile.as_deref()),
+        );
+
+        let hash = hook::load_sources(roots, profile.as_deref())
+            .ok()
+            .flatten()
+            .and_then(|sources| sources.hash());
+
+        if emitted != Some(hash) {
+            match emit(
+                &dir,
+                VariableOutputMode::from_options(&cmd.format),
+                cmd.clobber,
+                &config,
+            ) {
+                Ok(()) => emitted = Some(hash),
+                Err(err) => {
+                    eprintln!("shadowenv watch: {}", err);
+                    emitted = None;
+                }
+            }
+        }
+
+        wait_for_changes(&rx)?;
+    }
+}
+
+/// Block until the watcher reports something, then swallow the rest of the burst.
+pub fn wait_for_changes<T>(rx: &Receiver<T>) -> Result<(), Error> {
+    rx.recv()?;
+    loop {
+        match rx.recv_timeout(DEBOUNCE) {
+            Ok(_) => continue,
+            Err(RecvTimeoutError::Timeout) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// The directories whose contents can change which shadowenv applies to `dir`: `dir` and all of
+/// its ancestors (a `.shadowenv.d` could be created or removed in any of them), and the contents of
+/// every root currently in use, which includes their `parent` links.
+pub fn watch_paths(dir: &Path, roots: &[PathBuf], profile: Option<&str>) -> HashSet<PathBuf> {
+    let profile_dirs = roots.iter().filter_map(|root| {
+        let profile_dir = root
+            .join(loader::SHADOWENV_PROFILES_DIR_NAME)
+            .join(profile?);
+        profile_dir.is_dir().then_some(profile_dir)
+    });
+    dir.ancestors()
+        .map(Path::to_path_buf)
+        .chain(roots.iter().cloned())
+        .chain(profile_dirs)
+        .collect()
+}
+
+pub fn update_watches(
+    watcher: &mut RecommendedWatcher,
+    watched: &mut HashSet<PathBuf>,
+    wanted: HashSet<PathBuf>,
+) {
+    for path in watched.difference(&wanted) {
+        let _ = watcher.unwatch(path);
+    }
+    // Paths we fail to watch (e.g. for lack of permissions) are retried on the next change.
+    watched.retain(|path| wanted.contains(path));
+    for path in wanted {
+        if !watched.contains(&path) && watcher.watch(&path, RecursiveMode::NonRecursive).is_ok() {
+            watched.insert(path);
+        }
+    }
+}
+
+fn emit(dir: &Path, mode: VariableOutputMode, clobber: bool, config: &Config) -> Result<(), Error> {
+    let data = Shadowenv::from_env();
+    let shadowenv = match hook::load_env(
+        dir.to_path_buf(),
+        env::vars().collect(),
+        data,
+        true,
+        clobber,
+        config,
+    )? {
+        Some((shadowenv, _)) => shadowenv,
+        // Neither an active shadowenv nor one to activate: tell the consumer to go back to the
+        // environment we started with.
+        None => Shadowenv::new(env::vars().collect(), undo::Data::new(), 0, clobber),
+    };
+    // Unlike the hook's, this output isn't for an interactive shell: no activation banner.
+    hook::print_modifications(&Modifications::from_shadowenv(&shadowenv)?, &mode);
+    io::stdout().flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watch_paths_include_ancestors_and_roots() {
+        let dir = PathBuf::from("/a/b/c");
+        let roots = vec![
+            PathBuf::from("/a/b/.shadowenv.d"),
+            PathBuf::from("/a/.shadowenv.d"),
+        ];
+
+        let expected: HashSet<PathBuf> = [
+            "/a/b/c",
+            "/a/b",
+            "/a",
+            "/",
+            "/a/b/.shadowenv.d",
+            "/a/.shadowenv.d",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+
+        assert_eq!(watch_paths(&dir, &roots, None), expected);
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{anyhow, Error};
+use std::{
+    env, fs,
+    io::ErrorKind,
+    os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+/// Directory for per-user runtime files such as sockets.
+///
+/// This is `$XDG_RUNTIME_DIR/shadowenv` if the variable is set, and a per-user directory in the
+/// system temp dir otherwise. The directory is created (mode 0700) if it doesn't exist yet.
+pub fn runtime_dir() -> Result<PathBuf, Error> {
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("shadowenv"),
+        _ => env::temp_dir().join(format!("shadowenv-{}", unsafe { libc::getuid() })),
+    };
+    ensure_private_dir(&dir)?;
+    Ok(dir)
+}
+
+/// Directory for per-user state worth keeping across commands, such as error cooldowns.
+///
+/// This is `$XDG_STATE_HOME/shadowenv`, or `~/.local/state/shadowenv` if the variable isn't set.
+pub fn state_dir() -> Result<PathBuf, Error> {
+    let dir = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("shadowenv"),
+        _ => PathBuf::from(env::var("HOME")?).join(".local/state/shadowenv"),
+    };
+    ensure_private_dir(&dir)?;
+    Ok(dir)
+}
+
+/// Directory for state that only matters as long as the user's shells do: the runtime dir if
+/// `$XDG_RUNTIME_DIR` is set, since it's cleared on logout, and the state dir otherwise.
+pub fn session_dir() -> Result<PathBuf, Error> {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => runtime_dir(),
+        _ => state_dir(),
+    }
+}
+
+fn ensure_private_dir(dir: &Path) -> Result<(), Error> {
+    match fs::symlink_metadata(dir) {
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)?;
+        }
+        Err(err) => return Err(err.into()),
+        Ok(_) => (),
+    }
+    // The fallback location lives in a world-writable directory, so don't trust a directory
+    // somebody else could have created for us, or a symlink to one.
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(anyhow!("{} is not a directory", dir.display()));
+    }
+    if metadata.uid() != unsafe { libc::getuid() } {
+        return Err(anyhow!("{} is owned by another user", dir.display()));
+    }
+    if metadata.permissions().mode() & 0o077 != 0 {
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensure_private_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().join("shadowenv");
+        ensure_private_dir(&dir).unwrap();
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        ensure_private_dir(&dir).unwrap();
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[test]
+    fn test_ensure_private_dir_rejects_symlinks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let target = temp_dir.path().join("elsewhere");
+        fs::DirBuilder::new().mode(0o700).create(&target).unwrap();
+        let link = temp_dir.path().join("shadowenv");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        assert!(ensure_private_dir(&link).is_err());
+    }
+}